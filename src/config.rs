@@ -0,0 +1,991 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::canonicalize,
+    io,
+    path::{Path, PathBuf},
+};
+
+use dirs::{config_dir, home_dir};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use minijinja::{self, Environment, Syntax};
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+use tap::prelude::*;
+
+use crate::{
+    error::DotteryError,
+    logging::{log_debug, log_error},
+    packages::PackageManager,
+    processing::{env_vars, host_facts},
+};
+
+const CONFIG_DIR: &str = "dottery";
+const CONFIG_FILE: &str = "config.toml";
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Config {
+    pub paths: Paths,
+    #[serde(default)]
+    pub logging: Logging,
+    #[serde(default)]
+    pub sync: SyncConfig,
+    #[serde(default, skip_serializing_if = "Files::is_default")]
+    pub files: Files,
+    /// Package manager settings, by name of the manager
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub managers: HashMap<String, ManagerConfig>,
+    #[serde(default, skip_serializing_if = "Hooks::is_default")]
+    pub hooks: Hooks,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let mut home = home_dir().unwrap();
+        home.push(".dotfiles");
+
+        let dotfiles_path = home.to_str().unwrap();
+
+        Self {
+            paths: Paths {
+                dotfiles_path: dotfiles_path.into(),
+                manifest_file: default_manifest_file(),
+                canonicalize: true,
+                state_dir: None,
+            },
+            logging: Logging::default(),
+            sync: SyncConfig::default(),
+            files: Files::default(),
+            managers: HashMap::new(),
+            hooks: Hooks::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Returns the arguments passed to a package manager to install packages
+    pub fn install_args(&self, manager: PackageManager) -> Vec<&str> {
+        self.managers
+            .get(manager.command())
+            .and_then(|m| m.install_args.as_ref())
+            .map(|args| args.iter().map(String::as_str).collect())
+            .unwrap_or_else(|| manager.install_args())
+    }
+
+    /// Returns the maximum number of packages a package manager is given at once, if limited
+    pub fn batch_size(&self, manager: PackageManager) -> Option<usize> {
+        self.managers
+            .get(manager.command())
+            .and_then(|m| m.batch_size)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Paths {
+    pub dotfiles_path: String,
+    /// Name of the manifest in the dotfiles directory
+    #[serde(default = "default_manifest_file")]
+    pub manifest_file: String,
+    /// Whether to resolve `dotfiles_path` to an absolute path without symlinks
+    #[serde(default = "default_true")]
+    pub canonicalize: bool,
+    /// Directory the deployed files are recorded in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_dir: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_manifest_file() -> String {
+    "..toml".into()
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Logging {
+    /// Color theme of the output (`default`, `mono` or `high-contrast`)
+    pub theme: String,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Self {
+            theme: "default".into(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SyncConfig {
+    pub backend: SyncBackend,
+    /// Directory (possibly remote, e.g. `host:path`) to pull dotfiles from with `rsync`
+    pub source: Option<String>,
+    /// Git remote to pull from and push to, instead of the branch's upstream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+    /// Branch of the remote to pull from and push to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+}
+
+impl SyncConfig {
+    /// Remote to pull from and push to, if one is configured (`origin` if only the branch is)
+    pub fn remote(&self) -> Option<&str> {
+        self.remote
+            .as_deref()
+            .or(self.branch.as_ref().map(|_| DEFAULT_REMOTE))
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Files {
+    /// Manifests merged over `..toml` in order, `[".personal.toml"]` by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include: Option<Vec<String>>,
+    /// Extensions of templates that are always copied as-is, replacing the built-in ones
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub binary_extensions: Option<Vec<String>>,
+}
+
+impl Files {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Commands run around each deploy
+#[derive(Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Hooks {
+    /// Shell command run (in the dotfiles directory) before deploying
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_deploy: Option<String>,
+    /// Shell command run (in the dotfiles directory) after deploying
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_deploy: Option<String>,
+    /// Whether the deploy is aborted if `pre_deploy` fails
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub abort_on_failure: bool,
+    /// Shell commands run after deploying, if any file in their directory (or matching
+    /// their path, relative to the dotfiles directory) was written
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub on_change: HashMap<String, String>,
+}
+
+impl Hooks {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Remote synced with if only `sync.branch` is configured
+const DEFAULT_REMOTE: &str = "origin";
+/// Manifest merged over `..toml` if it exists, unless others are included explicitly
+const DEFAULT_INCLUDE: &str = ".personal.toml";
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ManagerConfig {
+    /// Arguments preceding the package names (e.g. `-S --needed` for pacman)
+    pub install_args: Option<Vec<String>>,
+    /// Maximum number of packages installed by a single invocation
+    pub batch_size: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncBackend {
+    #[default]
+    Git,
+    Rsync,
+    None,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Dotfiles {
+    pub packages: Vec<Package>,
+    pub dependencies: Option<Dependencies>,
+    /// Rules overriding where matching files are deployed
+    #[serde(default)]
+    pub map: Vec<MapRule>,
+    pub template_suffix: Option<TemplateSuffix>,
+    /// Directories to deploy into, by hostname
+    #[serde(default)]
+    pub targets: HashMap<String, String>,
+    /// Directories outside of the target directory that files may be deployed into
+    #[serde(default)]
+    pub allowed_targets: Vec<String>,
+    /// Sets of packages and manifests to use instead of all of them, by name
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+    /// Whether variables are also accessible at the top level of templates, not only
+    /// under `vars`
+    #[serde(default = "default_true")]
+    pub flat_vars: bool,
+    /// Commands that can be run with `dot run`, by name (read from the top-level `[tasks]`
+    /// table, not from `[dottery]`)
+    #[serde(skip)]
+    pub tasks: Option<HashMap<String, Task>>,
+    /// Delimiters used in templates and how undefined values are handled
+    #[serde(default)]
+    pub templating: Templating,
+    /// Environment variables available in templates
+    #[serde(default)]
+    pub env: EnvVars,
+    /// Commands run on deployed templates, by extension
+    #[serde(default)]
+    pub postprocess: HashMap<String, Postprocessor>,
+    #[serde(default)]
+    pub sass: Sass,
+}
+
+/// Options for compiling SASS templates
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Sass {
+    /// Whether deployed SASS files are removed once they're compiled, leaving only the CSS
+    #[serde(default)]
+    pub remove_source: bool,
+}
+
+/// Command that turns a deployed template into another file (e.g. compiles a stylesheet)
+#[derive(Clone, Debug, Deserialize)]
+pub struct Postprocessor {
+    /// Shell command, in which `{input}` and `{output}` are replaced with the paths
+    pub cmd: String,
+    /// Extension of the output, which is written next to the template's target
+    pub output: String,
+}
+
+/// Selection of packages and manifests, chosen with `--profile`
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Profile {
+    /// Names of the packages to install. If neither these nor `groups` are set, all
+    /// packages are.
+    pub packages: Option<Vec<String>>,
+    /// Groups of the packages to install
+    pub groups: Option<Vec<String>>,
+    /// Manifests (relative to the dotfiles directory) merged over the other ones
+    #[serde(default)]
+    pub include: Vec<String>,
+}
+
+impl Profile {
+    /// Whether the package is selected by the profile
+    pub fn selects(&self, package: &Package) -> bool {
+        let by_name = self
+            .packages
+            .as_ref()
+            .map(|ps| ps.iter().any(|p| p == package.name()));
+        let by_group = self
+            .groups
+            .as_ref()
+            .map(|gs| package.group().is_some_and(|g| gs.iter().any(|s| s == g)));
+
+        match (by_name, by_group) {
+            (None, None) => true,
+            (a, b) => a.unwrap_or(false) || b.unwrap_or(false),
+        }
+    }
+}
+
+/// Environment variables exposed to templates as `env`
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct EnvVars {
+    /// Whether to expose the whole environment instead of only the allowed variables
+    #[serde(default)]
+    pub all: bool,
+    /// Variables exposed in addition to the default ones (`HOME`, `USER`, etc.)
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+/// Template delimiters overriding the default ones (`{% %}`, `{{ }}`, `{# #}`)
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Templating {
+    /// Whether using undefined values is an error, rather than rendering them empty
+    #[serde(default)]
+    pub strict: bool,
+    pub block_start: Option<String>,
+    pub block_end: Option<String>,
+    pub variable_start: Option<String>,
+    pub variable_end: Option<String>,
+    pub comment_start: Option<String>,
+    pub comment_end: Option<String>,
+    /// Directory in `template/` containing partials that templates can include or import,
+    /// which aren't deployed themselves (`_partials` by default)
+    pub partials: Option<String>,
+}
+
+impl Templating {
+    pub fn partials(&self) -> &str {
+        self.partials.as_deref().unwrap_or("_partials")
+    }
+
+    /// Delimiters to render templates with, the default ones where unset
+    pub fn syntax(&self) -> Syntax {
+        let default = Syntax::default();
+        let pick = |custom: &Option<String>, default| match custom {
+            Some(s) => s.clone().into(),
+            None => default,
+        };
+
+        Syntax {
+            block_start: pick(&self.block_start, default.block_start),
+            block_end: pick(&self.block_end, default.block_end),
+            variable_start: pick(&self.variable_start, default.variable_start),
+            variable_end: pick(&self.variable_end, default.variable_end),
+            comment_start: pick(&self.comment_start, default.comment_start),
+            comment_end: pick(&self.comment_end, default.comment_end),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Task {
+    /// Shell command run in the dotfiles directory
+    pub cmd: String,
+    pub description: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TemplateSuffix {
+    /// Suffix stripped from target names of templates (e.g. `.tmpl`)
+    pub suffix: String,
+    /// Globs of files or directories (relative to the dotfiles directory) where the
+    /// suffix is stripped. If unset, it is stripped from all templates.
+    #[serde(default, deserialize_with = "deserialize_globs")]
+    pub only_in: Option<GlobSet>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MapRule {
+    /// Pattern matched against the whole path of a file, relative to the dotfiles directory
+    #[serde(deserialize_with = "deserialize_path_regex")]
+    pub from: Regex,
+    /// Target path, which can reference captures of `from` (`$1`, `${name}`)
+    pub to: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Package {
+    name: String,
+    from_aur: bool,
+    /// Command that checks whether the package works after installation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verify: Option<String>,
+    /// Command run after the package is installed (e.g. to enable its service)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_install: Option<String>,
+    /// Name of the group the package belongs to (e.g. `fonts`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    /// Names the package is installed under with some package managers, by their commands
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aliases: Option<HashMap<String, String>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Dependencies {
+    pub required: Option<Vec<Package>>,
+    pub optional: Option<Vec<Package>>,
+}
+
+fn deserialize_path_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    String::deserialize(deserializer)
+        .and_then(|s| Regex::new(&format!("^(?:{s})$")).map_err(serde::de::Error::custom))
+}
+
+fn deserialize_globs<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<GlobSet>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .try_fold(GlobSetBuilder::new(), |mut builder, g| {
+            builder.add(Glob::new(g)?);
+            Ok(builder)
+        })
+        .and_then(|builder| builder.build())
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+impl TemplateSuffix {
+    /// Strips the suffix from the target path, if the file is in scope
+    pub fn strip(&self, relative: &str, target: String) -> String {
+        let in_scope = match self.only_in {
+            Some(ref globs) => Path::new(relative).ancestors().any(|p| globs.is_match(p)),
+            None => true,
+        };
+
+        match target.strip_suffix(&self.suffix) {
+            Some(stripped) if in_scope => stripped.to_string(),
+            _ => target,
+        }
+    }
+}
+
+impl Package {
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    #[allow(clippy::wrong_self_convention)]
+    pub fn from_aur(&self) -> bool {
+        self.from_aur
+    }
+
+    pub fn verify(&self) -> Option<&str> {
+        self.verify.as_deref()
+    }
+
+    pub fn post_install(&self) -> Option<&str> {
+        self.post_install.as_deref()
+    }
+
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    /// Name the package is installed under with the package manager
+    pub fn install_name(&self, manager: PackageManager) -> &str {
+        self.aliases
+            .as_ref()
+            .and_then(|a| a.get(manager.command()))
+            .unwrap_or(&self.name)
+    }
+}
+
+/// Reads template substitutions and the `dottery` section from the manifest in the given
+/// directory, with the included manifests merged over it. By default, `.personal.toml`
+/// is included if it exists. The manifests included by the profile, if any, are merged
+/// last.
+pub fn read_settings<P: AsRef<Path>>(
+    dir: P,
+    manifest: &str,
+    include: Option<&[String]>,
+    profile: Option<&str>,
+) -> Result<(toml::Value, Dotfiles), DotteryError> {
+    let mut settings = read_manifest(dir.as_ref().join(manifest))?;
+
+    match include {
+        Some(files) => {
+            for f in files {
+                let path = dir.as_ref().join(f);
+
+                if path.exists() {
+                    merge_settings(&mut settings, read_manifest(path)?);
+                } else {
+                    log_error(&format!(
+                        "included manifest `{f}` not found in dotfiles directory"
+                    ));
+                }
+            }
+        }
+        None => {
+            let personal = dir.as_ref().join(DEFAULT_INCLUDE);
+
+            if personal.exists() {
+                merge_settings(&mut settings, read_manifest(personal)?);
+            }
+        }
+    }
+
+    if let Some(name) = profile {
+        let profile = settings
+            .get("dottery")
+            .and_then(|d| d.get("profiles"))
+            .and_then(|ps| ps.get(name))
+            .ok_or_else(|| DotteryError::UnknownProfile(name.into(), manifest.into()))?;
+        let profile = Profile::deserialize(profile.clone())
+            .map_err(|e| DotteryError::InvalidSection(manifest.into(), e))?;
+
+        for f in profile.include {
+            merge_settings(&mut settings, read_manifest(dir.as_ref().join(f))?);
+        }
+    }
+
+    merge_host_settings(&mut settings);
+
+    let dotfiles = load_dotfiles(&mut settings, manifest)?;
+
+    Ok((settings, dotfiles))
+}
+
+/// Removes the `[dottery]` section and the `[tasks]` table from the settings (read from
+/// `manifest`) and parses them
+fn load_dotfiles(settings: &mut toml::Value, manifest: &str) -> Result<Dotfiles, DotteryError> {
+    let dottery = settings
+        .as_table_mut()
+        .and_then(|ss| ss.remove("dottery"))
+        .ok_or_else(|| DotteryError::MissingSection(manifest.into()))?;
+
+    let mut dotfiles = Dotfiles::deserialize(dottery)
+        .map_err(|e| DotteryError::InvalidSection(manifest.into(), e))?;
+
+    if let Some(tasks) = settings.as_table_mut().and_then(|ss| ss.remove("tasks")) {
+        dotfiles.tasks = Some(
+            HashMap::deserialize(tasks)
+                .map_err(|e| DotteryError::InvalidTasks(manifest.into(), e))?,
+        );
+    }
+
+    // NOTE: Conflicting delimiters are only detected by trying them
+    Environment::new()
+        .set_syntax(dotfiles.templating.syntax())
+        .map_err(|e| DotteryError::Delimiters(manifest.into(), e))?;
+
+    Ok(dotfiles)
+}
+
+/// Merges the `hosts.<hostname>` table for this machine (if any) over the settings and
+/// removes the `hosts` table
+fn merge_host_settings(settings: &mut toml::Value) {
+    let Some(hosts) = settings.as_table_mut().and_then(|ss| ss.remove("hosts")) else {
+        return;
+    };
+
+    let hostname = gethostname::gethostname().to_string_lossy().to_string();
+
+    if let Some(overlay) = hosts.get(&hostname) {
+        merge_settings(settings, overlay.clone());
+    }
+}
+
+/// Reads a manifest file. It's a template itself, rendered with only the `host` facts and
+/// the default `env` variables (not its own variables, nor the ones it allows).
+fn read_manifest<P: AsRef<Path>>(path: P) -> Result<toml::Value, DotteryError> {
+    let path = path.as_ref().to_path_buf();
+
+    log_debug(&format!("Reading `{}`", path.display()));
+
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(error) => return Err(DotteryError::ReadManifest { path, error }),
+    };
+    let rendered = match Environment::new().render_str(
+        &contents,
+        minijinja::context! { host => host_facts(), env => env_vars(&EnvVars::default()) },
+    ) {
+        Ok(r) => r,
+        Err(error) => return Err(DotteryError::Render { path, error }),
+    };
+
+    toml::from_str(&rendered).map_err(|error| DotteryError::ParseManifest { path, error })
+}
+
+/// Merges `overlay` into `base`: tables are merged recursively, arrays of tables (like
+/// `packages`) are concatenated and other values are replaced
+fn merge_settings(base: &mut toml::Value, overlay: toml::Value) {
+    let is_array_of_tables = |vs: &[toml::Value]| vs.iter().all(toml::Value::is_table);
+
+    match (base, overlay) {
+        (toml::Value::Table(b), toml::Value::Table(o)) => {
+            o.into_iter().for_each(|(k, v)| match b.get_mut(&k) {
+                Some(bv) => merge_settings(bv, v),
+                None => {
+                    b.insert(k, v);
+                }
+            })
+        }
+        (toml::Value::Array(b), toml::Value::Array(o))
+            if is_array_of_tables(b) && is_array_of_tables(&o) =>
+        {
+            b.extend(o)
+        }
+        (b, o) => *b = o,
+    }
+}
+
+/// Sets a value in the settings by its dotted key (e.g. `colors.background`), creating the
+/// tables along the way
+pub fn set_setting(
+    settings: &mut toml::Value,
+    key: &str,
+    value: toml::Value,
+) -> Result<(), String> {
+    let (tables, name) = key.rsplit_once('.').unwrap_or(("", key));
+
+    let table = tables
+        .split('.')
+        .filter(|k| !k.is_empty())
+        .try_fold(settings, |table, k| {
+            table
+                .as_table_mut()
+                .map(|t| t.entry(k).or_insert_with(|| toml::Table::new().into()))
+                .ok_or_else(|| format!("cannot set `{key}`, a parent of it is not a table"))
+        })?;
+
+    match table.as_table_mut() {
+        Some(t) => {
+            t.insert(name.into(), value);
+            Ok(())
+        }
+        None => Err(format!("cannot set `{key}`, a parent of it is not a table")),
+    }
+}
+
+/// Returns the given path of the config file, or the default one
+pub fn config_path(config_file: Option<PathBuf>) -> PathBuf {
+    config_file.unwrap_or_else(|| {
+        config_dir()
+            .unwrap()
+            .tap_mut(|cf| cf.push(CONFIG_DIR))
+            .tap_mut(|cf| cf.push(CONFIG_FILE))
+    })
+}
+
+/// Points the config file at a dotfiles directory, keeping its other settings. The
+/// config file is created if it doesn't exist.
+pub fn set_dotfiles_path(config_file: &Path, dotfiles_path: &str) -> io::Result<()> {
+    let config = match std::fs::read_to_string(config_file) {
+        Ok(s) => {
+            let invalid = |e: &dyn Display| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid config file `{}`: {e}", config_file.display()),
+                )
+            };
+            // NOTE: Not deserialized into `Config`, so that variables stay unexpanded
+            let mut config: toml::Table = toml::from_str(&s).map_err(|e| invalid(&e))?;
+            config
+                .entry("paths")
+                .or_insert_with(|| toml::Table::new().into())
+                .as_table_mut()
+                .ok_or_else(|| invalid(&"`paths` isn't a table"))?
+                .insert("dotfiles_path".into(), dotfiles_path.into());
+
+            toml::to_string(&config)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut config = Config::default();
+            config.paths.dotfiles_path = dotfiles_path.into();
+
+            toml::to_string(&config)
+        }
+        Err(e) => return Err(e),
+    }
+    .expect("failed to convert config to `toml`");
+
+    std::fs::create_dir_all(config_file.parent().unwrap())?;
+    std::fs::write(config_file, config)
+}
+
+/// Reads the config file from the given path or the default location, writing the
+/// default config if it doesn't exist
+pub fn read_config(
+    config_file: Option<PathBuf>,
+    canonicalize_path: bool,
+) -> Result<Config, DotteryError> {
+    let config_file = config_path(config_file);
+
+    match std::fs::read_to_string(config_file.clone()) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            std::fs::create_dir_all(config_file.clone().parent().unwrap())?;
+
+            let config_default = Config::default();
+
+            toml::to_string(&config_default)
+                .expect("failed to convert config to `toml`")
+                .pipe(|s| std::fs::write(config_file, s))
+                .expect("failed to write config file");
+
+            Ok(config_default)
+        }
+        Err(e) => {
+            log_error(&format!("failed to read config file: {e}"));
+
+            Ok(Config::default())
+        }
+        Ok(s) => toml::from_str(&s)
+            .map(|v: toml::Value| v.tap_mut(expand_config_vars))
+            .and_then(Config::deserialize)
+            .map_err(|error| DotteryError::Config {
+                path: config_file,
+                error,
+            }),
+    }
+    .tap_ok_mut(|c| {
+        if !(canonicalize_path && c.paths.canonicalize) {
+            return;
+        }
+
+        // NOTE: A missing directory is reported by `validate_dotfiles_dir`
+        if let Ok(path) = canonicalize(&c.paths.dotfiles_path) {
+            c.paths.dotfiles_path = path.to_str().unwrap().to_string();
+        }
+    })
+}
+
+/// Checks that the dotfiles directory exists and contains the manifest, explaining how to
+/// fix it otherwise
+pub fn validate_dotfiles_dir(config: &Config, config_file: &Path) -> Result<(), DotteryError> {
+    let dir = Path::new(&config.paths.dotfiles_path);
+    let manifest = &config.paths.manifest_file;
+
+    if !dir.exists() {
+        return Err(DotteryError::DotfilesDir(format!(
+            "dotfiles directory `{}` doesn't exist. Set `paths.dotfiles_path` in `{}` \
+             or create it with `dot init`",
+            dir.display(),
+            config_file.display()
+        )));
+    }
+
+    if !dir.is_dir() {
+        return Err(DotteryError::DotfilesDir(format!(
+            "dotfiles path `{}` is not a directory. Set `paths.dotfiles_path` in `{}` \
+             to the directory containing `{manifest}`",
+            dir.display(),
+            config_file.display()
+        )));
+    }
+
+    if !dir.join(manifest).is_file() {
+        return Err(DotteryError::MissingManifest(format!(
+            "dotfiles directory `{}` has no `{manifest}`. Create it (e.g. with \
+             `dot init --force {}`), or set `paths.manifest_file` in `{}` if it's named \
+             differently",
+            dir.display(),
+            dir.display(),
+            config_file.display()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Expands environment variables in the config like `expand_env_vars`, except in `hooks`,
+/// whose commands are left for the shell to expand
+fn expand_config_vars(config: &mut toml::Value) {
+    if let toml::Value::Table(t) = config {
+        t.iter_mut()
+            .filter(|(k, _)| *k != "hooks")
+            .for_each(|(_, v)| expand_env_vars(v));
+    }
+}
+
+/// Recursively expands environment variables (`$VAR`, `${VAR}`) and a leading `~`
+/// in all string values
+fn expand_env_vars(value: &mut toml::Value) {
+    match value {
+        toml::Value::String(s) => *s = expand_str(s),
+        toml::Value::Array(vs) => vs.iter_mut().for_each(expand_env_vars),
+        toml::Value::Table(t) => t.iter_mut().for_each(|(_, v)| expand_env_vars(v)),
+        _ => (),
+    }
+}
+
+/// Performs shell-style variable expansion on a string. Unset variables are left as-is.
+pub fn expand_str(s: &str) -> String {
+    let mut output = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+
+    if s == "~" || s.starts_with("~/") {
+        if let Some(home) = home_dir() {
+            output.push_str(&home.to_string_lossy());
+            chars.next();
+        }
+    }
+
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            output.push(c);
+            continue;
+        }
+
+        let braced = chars.next_if_eq(&'{').is_some();
+        let mut name = String::new();
+
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            name.push(c);
+        }
+
+        let closed = braced && chars.next_if_eq(&'}').is_some();
+
+        match std::env::var(&name) {
+            Ok(v) if !name.is_empty() && braced == closed => output.push_str(&v),
+            _ => {
+                output.push('$');
+
+                if braced {
+                    output.push('{');
+                }
+
+                output.push_str(&name);
+
+                if closed {
+                    output.push('}');
+                }
+            }
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn includes_append_packages() {
+        let dir = TempDir::new().unwrap();
+
+        std::fs::write(
+            dir.path().join("..toml"),
+            r##"
+            [dottery]
+            packages = [{ name = "kitty", from_aur = false }]
+
+            [colors]
+            background = "#000000"
+            foreground = "#ffffff"
+            "##,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join(".personal.toml"),
+            r##"
+            [[dottery.packages]]
+            name = "proton"
+            from_aur = true
+
+            [colors]
+            background = "#222222"
+            "##,
+        )
+        .unwrap();
+
+        let (settings, dotfiles) = read_settings(dir.path(), "..toml", None, None).unwrap();
+
+        let names: Vec<_> = dotfiles.packages.iter().map(Package::name).collect();
+        assert_eq!(names, ["kitty", "proton"]);
+
+        assert_eq!(settings["colors"]["background"].as_str(), Some("#222222"));
+        assert_eq!(settings["colors"]["foreground"].as_str(), Some("#ffffff"));
+    }
+
+    #[test]
+    fn set_dotted_keys() {
+        let mut settings: toml::Value = toml::from_str(
+            r##"
+            [colors]
+            background = "#000000"
+            "##,
+        )
+        .unwrap();
+
+        set_setting(&mut settings, "colors.background", "#222222".into()).unwrap();
+        set_setting(&mut settings, "bar.font.size", 12.into()).unwrap();
+
+        assert_eq!(settings["colors"]["background"].as_str(), Some("#222222"));
+        assert_eq!(settings["bar"]["font"]["size"].as_integer(), Some(12));
+        assert!(set_setting(&mut settings, "colors.background.dark", true.into()).is_err());
+    }
+
+    #[test]
+    fn conflicting_delimiters_are_rejected() {
+        let mut settings: toml::Value = toml::from_str(
+            r#"
+            [dottery]
+            packages = []
+
+            [dottery.templating]
+            block_start = "<%"
+            variable_start = "<%"
+            "#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            load_dotfiles(&mut settings, "..toml"),
+            Err(DotteryError::Delimiters(..))
+        ));
+    }
+
+    #[test]
+    fn invalid_config_is_kept() {
+        let dir = TempDir::new().unwrap();
+        let config_file = dir.path().join("config.toml");
+        std::fs::write(&config_file, "paths = [").unwrap();
+
+        let result = set_dotfiles_path(&config_file, "~/dots");
+        let contents = std::fs::read_to_string(&config_file).unwrap();
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(contents, "paths = [");
+    }
+
+    #[test]
+    fn hooks_are_left_unexpanded() {
+        let mut config: toml::Value = toml::from_str(
+            r#"
+            [paths]
+            dotfiles_path = "~/dots"
+
+            [hooks]
+            post_deploy = "echo '$HOME'"
+            "#,
+        )
+        .unwrap();
+
+        expand_config_vars(&mut config);
+
+        assert_ne!(config["paths"]["dotfiles_path"].as_str(), Some("~/dots"));
+        assert_eq!(
+            config["hooks"]["post_deploy"].as_str(),
+            Some("echo '$HOME'")
+        );
+    }
+
+    #[test]
+    fn tasks_are_read_from_the_top_level() {
+        let mut settings: toml::Value = toml::from_str(
+            r#"
+            [dottery]
+            packages = []
+
+            [tasks.rebuild-font-cache]
+            cmd = "fc-cache -f"
+            "#,
+        )
+        .unwrap();
+
+        let dotfiles = load_dotfiles(&mut settings, "..toml").unwrap();
+
+        assert_eq!(
+            dotfiles.tasks.unwrap()["rebuild-font-cache"].cmd,
+            "fc-cache -f"
+        );
+        assert!(settings.get("tasks").is_none());
+    }
+
+    #[test]
+    fn merge_nested_tables() {
+        let mut base: toml::Value = toml::from_str(
+            r##"
+            [colors.bar]
+            background = "#000000"
+            foreground = "#ffffff"
+            font = ["Fira Code", "Noto"]
+            "##,
+        )
+        .unwrap();
+        let overlay: toml::Value = toml::from_str(
+            r##"
+            [colors.bar]
+            background = "#222222"
+            font = ["Iosevka"]
+            "##,
+        )
+        .unwrap();
+
+        merge_settings(&mut base, overlay);
+
+        let bar = &base["colors"]["bar"];
+        assert_eq!(bar["background"].as_str(), Some("#222222"));
+        assert_eq!(bar["foreground"].as_str(), Some("#ffffff"));
+        assert_eq!(bar["font"].as_array().map(Vec::len), Some(1));
+    }
+}
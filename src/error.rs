@@ -0,0 +1,124 @@
+use std::{
+    error::Error,
+    fmt::{self, Debug, Display},
+    io,
+    path::PathBuf,
+};
+
+/// Errors that `dot` can't continue after
+pub enum DotteryError {
+    /// The config file couldn't be parsed
+    Config {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+    /// The dotfiles directory doesn't exist or isn't a directory, along with how to fix it
+    DotfilesDir(String),
+    /// The dotfiles directory has no manifest, along with how to fix it
+    MissingManifest(String),
+    /// A manifest couldn't be read
+    ReadManifest { path: PathBuf, error: io::Error },
+    /// A manifest isn't valid TOML
+    ParseManifest {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+    /// A template (like the manifest itself) couldn't be rendered
+    Render {
+        path: PathBuf,
+        error: minijinja::Error,
+    },
+    /// The manifest has no `[dottery]` section
+    MissingSection(String),
+    /// The `[dottery]` section of the manifest couldn't be parsed
+    InvalidSection(String, toml::de::Error),
+    /// The `[tasks]` table of the manifest couldn't be parsed
+    InvalidTasks(String, toml::de::Error),
+    /// The template delimiters set in the manifest can't be used together
+    Delimiters(String, minijinja::Error),
+    /// The profile isn't defined in the manifest
+    UnknownProfile(String, String),
+    /// None of the supported package managers is installed
+    NoPackageManager(String),
+    /// A git command failed
+    Git(String),
+    /// Any other I/O error
+    Io(io::Error),
+}
+
+impl Display for DotteryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Config { path, error } => write!(
+                f,
+                "failed to parse config file `{}`: {}",
+                path.display(),
+                error.message()
+            ),
+            Self::DotfilesDir(msg) | Self::MissingManifest(msg) | Self::Git(msg) => {
+                f.write_str(msg)
+            }
+            Self::ReadManifest { path, error } => {
+                write!(f, "failed to read `{}`: {error}", path.display())
+            }
+            // NOTE: Parse errors include the line and column, along with the offending line
+            Self::ParseManifest { path, error } => {
+                write!(f, "failed to parse `{}`: {error}", path.display())
+            }
+            Self::Render { path, error } => {
+                write!(f, "failed to render `{}`: {error}", path.display())
+            }
+            Self::MissingSection(manifest) => {
+                write!(f, "`{manifest}` has no `[dottery]` section")
+            }
+            Self::InvalidSection(manifest, error) => write!(
+                f,
+                "invalid `[dottery]` section in `{manifest}`: {}",
+                error.message()
+            ),
+            Self::InvalidTasks(manifest, error) => {
+                write!(f, "invalid `[tasks]` in `{manifest}`: {}", error.message())
+            }
+            Self::Delimiters(manifest, error) => {
+                write!(f, "invalid template delimiters in `{manifest}`: {error}")
+            }
+            Self::UnknownProfile(name, manifest) => {
+                write!(f, "no profile named `{name}` in `{manifest}`")
+            }
+            Self::NoPackageManager(supported) => {
+                write!(
+                    f,
+                    "no supported package manager found (looked for {supported})"
+                )
+            }
+            Self::Io(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+// NOTE: `main` prints the error it returns with `Debug`
+impl Debug for DotteryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Error for DotteryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Config { error, .. }
+            | Self::ParseManifest { error, .. }
+            | Self::InvalidSection(_, error)
+            | Self::InvalidTasks(_, error) => Some(error),
+            Self::ReadManifest { error, .. } | Self::Io(error) => Some(error),
+            Self::Render { error, .. } | Self::Delimiters(_, error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DotteryError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
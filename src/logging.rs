@@ -0,0 +1,233 @@
+use std::{
+    fmt::Display,
+    process,
+    sync::{
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+        OnceLock,
+    },
+};
+
+use owo_colors::{OwoColorize, Style};
+
+static THEME: OnceLock<Theme> = OnceLock::new();
+/// Number of errors and warnings logged so far
+static ISSUES: AtomicUsize = AtomicUsize::new(0);
+/// Most detailed level of messages that are printed
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Normal as u8);
+
+/// Amount of detail in the output
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Only errors
+    Quiet,
+    Normal,
+    Verbose,
+    Debug,
+}
+
+impl Level {
+    /// Level set by the `-v`/`-q` flags
+    pub fn from_flags(verbosity: u8, quiet: bool) -> Self {
+        match (quiet, verbosity) {
+            (true, _) => Self::Quiet,
+            (false, 0) => Self::Normal,
+            (false, 1) => Self::Verbose,
+            (false, _) => Self::Debug,
+        }
+    }
+}
+
+/// Styles used by the log helpers
+#[derive(Clone, Copy, Debug)]
+pub struct Theme {
+    prompt: Style,
+    message: Style,
+    error: Style,
+    warning: Style,
+    identical: Style,
+    modified: Style,
+    missing: Style,
+    extra: Style,
+}
+
+/// State of a file compared to what is expected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileStatus {
+    Identical,
+    Modified,
+    Missing,
+    Extra,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            prompt: Style::new().bright_black(),
+            message: Style::new().bold(),
+            error: Style::new().bright_red(),
+            warning: Style::new().bright_yellow(),
+            identical: Style::new().green(),
+            modified: Style::new().yellow(),
+            missing: Style::new().red(),
+            extra: Style::new().cyan(),
+        }
+    }
+}
+
+impl Theme {
+    /// Theme without any colors
+    fn mono() -> Self {
+        Self {
+            prompt: Style::new(),
+            message: Style::new().bold(),
+            error: Style::new().bold(),
+            warning: Style::new().bold(),
+            identical: Style::new(),
+            modified: Style::new(),
+            missing: Style::new(),
+            extra: Style::new(),
+        }
+    }
+
+    /// Theme that is readable on both light and dark terminals
+    fn high_contrast() -> Self {
+        Self {
+            prompt: Style::new().blue().bold(),
+            message: Style::new().bold(),
+            error: Style::new().red().bold(),
+            warning: Style::new().magenta().bold(),
+            identical: Style::new().green().bold(),
+            modified: Style::new().magenta().bold(),
+            missing: Style::new().red().bold(),
+            extra: Style::new().blue().bold(),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "default" => Some(Self::default()),
+            "mono" => Some(Self::mono()),
+            "high-contrast" => Some(Self::high_contrast()),
+            _ => None,
+        }
+    }
+}
+
+/// Sets the theme used by all further logging. Only the first call has an effect.
+pub fn set_theme(theme: Theme) {
+    let _ = THEME.set(theme);
+}
+
+fn theme() -> Theme {
+    THEME.get().copied().unwrap_or_default()
+}
+
+/// Sets the most detailed level of messages that are printed
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+fn enabled(level: Level) -> bool {
+    LEVEL.load(Ordering::Relaxed) >= level as u8
+}
+
+/// Returns the number of errors and warnings logged so far
+pub fn issue_count() -> usize {
+    ISSUES.load(Ordering::Relaxed)
+}
+
+pub fn log_msg(msg: &str) {
+    if !enabled(Level::Normal) {
+        return;
+    }
+
+    let theme = theme();
+
+    println!("{} {}", ">>".style(theme.prompt), msg.style(theme.message));
+}
+
+pub fn log_error(msg: &str) {
+    let theme = theme();
+
+    ISSUES.fetch_add(1, Ordering::Relaxed);
+
+    eprintln!(
+        "{} {}",
+        "ERROR:".style(theme.error),
+        msg.style(theme.message)
+    );
+}
+
+/// Logs an error and exits with code `1`, for errors that `dot` can't continue after
+pub fn fail(msg: &str) -> ! {
+    log_error(msg);
+    process::exit(1);
+}
+
+pub fn log_warning(msg: &str) {
+    let theme = theme();
+
+    ISSUES.fetch_add(1, Ordering::Relaxed);
+
+    if !enabled(Level::Normal) {
+        return;
+    }
+
+    eprintln!(
+        "{} {}",
+        "WARNING:".style(theme.warning),
+        msg.style(theme.message)
+    );
+}
+
+/// Prints a line of progress (like the path of a processed file) without decoration
+pub fn log_progress(msg: &str) {
+    if enabled(Level::Normal) {
+        println!("{msg}");
+    }
+}
+
+/// Prints a message only with `-v`
+pub fn log_verbose(msg: &str) {
+    if enabled(Level::Verbose) {
+        log_msg(msg);
+    }
+}
+
+/// Prints a message only with `-vv`
+pub fn log_debug(msg: &str) {
+    if enabled(Level::Debug) {
+        let theme = theme();
+
+        println!("{} {msg}", "..".style(theme.prompt));
+    }
+}
+
+/// Prints an item of a checklist, marked by whether the check passed
+pub fn log_check(passed: bool, msg: &str) {
+    let theme = theme();
+
+    if passed {
+        println!("{} {msg}", "✓".style(theme.identical));
+    } else {
+        println!("{} {msg}", "✗".style(theme.missing));
+    }
+}
+
+/// Prints a file path along with its status
+pub fn log_status(status: FileStatus, path: &str) {
+    let theme = theme();
+
+    let (label, style) = match status {
+        FileStatus::Identical => ("identical", theme.identical),
+        FileStatus::Modified => ("modified", theme.modified),
+        FileStatus::Missing => ("missing", theme.missing),
+        FileStatus::Extra => ("extra", theme.extra),
+    };
+
+    println!("{:>10} {path}", label.style(style));
+}
+
+pub fn log_on_err<T, E: Display>(result: Result<T, E>) {
+    let _ = result.map_err(|e| log_error(&format!("{e}")));
+}
@@ -1,40 +1,108 @@
+mod config;
+mod error;
+mod logging;
+mod packages;
+mod processing;
+mod state;
+mod watch;
+
 use std::{
-    ffi::OsStr,
-    fmt::Display,
-    fs::canonicalize,
-    io::{self, BufReader, Read, Seek},
-    path::{Component, Path},
-    process::{self, ExitStatus, Stdio},
+    collections::{BTreeMap, BTreeSet, HashSet},
+    io,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    process,
+    sync::Mutex,
 };
 
-use bindet;
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 use cmd_lib::run_cmd;
-use dirs::{config_dir, home_dir};
-use minijinja::{self, Environment};
-use owo_colors::OwoColorize;
-use serde::{Deserialize, Serialize};
+use config::{
+    config_path, read_config, read_settings, set_setting, validate_dotfiles_dir, Config, Dotfiles,
+    Package, SyncBackend,
+};
+use dirs::home_dir;
+use error::DotteryError;
+use logging::{
+    fail, log_check, log_error, log_msg, log_on_err, log_status, log_verbose, log_warning,
+    FileStatus, Level, Theme,
+};
+use packages::{
+    command_exists, filter_packages, install_pkgs, run_post_install, verify_pkgs, PackageManager,
+};
+use processing::{
+    copy_raw, deploy_status, deployed_targets, managed_files, process_templates, prompt,
+    sass_compiler, DeployOptions, DeployResults,
+};
+use state::{hash_file, state_path, DeployState};
 use tap::prelude::*;
-use toml;
-use walkdir::WalkDir;
-
-// TODO:
-// - Add verbosity
-
-const CONFIG_DIR: &str = "dottery";
-const CONFIG_FILE: &str = "config.toml";
 
 #[derive(Parser)]
 #[command(version)]
 struct Args {
     #[command(subcommand)]
     command: Command,
+    /// Read the config from this file instead of the default location
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Use the dotfiles path as-is, without resolving symlinks
+    #[arg(long, global = true)]
+    no_canonicalize: bool,
+    /// Exit with an error if any warnings or errors were reported
+    #[arg(long, global = true)]
+    fail_on_warning: bool,
+    /// Print more details about what's being done (twice for even more)
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+    /// Use a profile defined in `..toml`, which selects the packages and manifests
+    #[arg(long, global = true)]
+    profile: Option<String>,
+    /// Only print errors (and the output of commands like `locate`)
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+enum Format {
+    /// Human-readable log
+    #[default]
+    Text,
+    /// JSON array of the actions taken (or the items listed)
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Listing {
+    /// Configured packages
+    Packages,
+    /// Raw files and templates, along with their targets
+    Dotfiles,
 }
 
 #[derive(Subcommand, Clone)]
 enum Command {
     /// Install configured packages
-    Install { packages: Option<Vec<String>> },
+    Install {
+        packages: Option<Vec<String>>,
+        /// Exit with an error if a package fails verification
+        #[arg(short, long)]
+        strict: bool,
+        /// Don't ask for any confirmation (neither before installing, nor by the package manager)
+        #[arg(short = 'y', long, visible_alias = "yes")]
+        noconfirm: bool,
+        /// Only install packages of this group
+        #[arg(short, long)]
+        group: Option<String>,
+        /// Don't install these packages (can be repeated or comma-separated)
+        #[arg(long, value_name = "PKG", value_delimiter = ',')]
+        skip: Vec<String>,
+        /// Only install packages from the AUR
+        #[arg(long, conflicts_with = "no_aur")]
+        aur_only: bool,
+        /// Don't install packages from the AUR
+        #[arg(long)]
+        no_aur: bool,
+    },
     /// Install dependencies
     InstallDeps {
         /// Only install required dependencies
@@ -43,9 +111,31 @@ enum Command {
         /// Only install optional dependencies
         #[arg(short, long)]
         optional: bool,
+        /// Print the dependencies that would be installed, without installing them
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Don't ask for any confirmation (neither before installing, nor by the package manager)
+        #[arg(short = 'y', long, visible_alias = "yes")]
+        noconfirm: bool,
     },
     /// Synchronize local dotfiles with remote repo
-    Sync,
+    Sync {
+        /// Commit local changes and push them after pulling (only with git)
+        #[arg(short, long)]
+        push: bool,
+        /// Message of the commit with local changes
+        #[arg(short, long, requires = "push", default_value = DEFAULT_COMMIT_MESSAGE)]
+        message: String,
+        /// Stash local changes before pulling and re-apply them afterwards
+        #[arg(short, long)]
+        stash: bool,
+        /// Git remote to sync with (instead of `sync.remote`)
+        #[arg(long)]
+        remote: Option<String>,
+        /// Branch of the remote to sync with (instead of `sync.branch`)
+        #[arg(long)]
+        branch: Option<String>,
+    },
     /// Print dotfiles directory
     Locate,
     /// Process and copy templates and raw dotfiles to their locations
@@ -57,423 +147,1370 @@ enum Command {
         /// Only process templates
         #[arg(short, long)]
         template: bool,
+        /// Never overwrite existing files, report skipped ones instead
+        #[arg(long)]
+        only_new: bool,
+        /// Ask what to do with existing files that would be changed
+        #[arg(short, long, conflicts_with_all = ["only_new", "dry_run"])]
+        interactive: bool,
+        /// Print what would be deployed where, without writing anything
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Symlink raw files into place instead of copying them
+        #[arg(short, long, conflicts_with = "git_ref")]
+        link: bool,
+        /// Back up existing files before overwriting them
+        #[arg(short, long, conflicts_with = "only_new")]
+        backup: bool,
+        /// Show how existing files would change
+        #[arg(short, long)]
+        diff: bool,
+        /// Make read-only targets writable to overwrite them
+        #[arg(short, long, conflicts_with = "only_new")]
+        force: bool,
+        /// Don't compile SASS templates (they're still deployed)
+        #[arg(long)]
+        no_sass: bool,
+        /// Deploy files from a git ref (branch, tag, commit) instead of the working tree
+        #[arg(long = "ref", value_name = "REF")]
+        git_ref: Option<String>,
+        /// Directory to deploy dotfiles into (instead of the host's configured one or home)
+        #[arg(long, value_name = "DIR")]
+        target: Option<String>,
+        /// Fail to render templates that use undefined values
+        #[arg(long)]
+        strict: bool,
+        /// Output format
+        #[arg(long, value_enum, default_value_t, conflicts_with_all = ["interactive", "diff"])]
+        format: Format,
+        /// Override a value used in templates, e.g. `colors.background=#000000` (can be
+        /// repeated). Values are parsed as TOML, falling back to strings.
+        #[arg(long, value_name = "KEY=VALUE", value_parser = parse_assignment)]
+        set: Vec<(String, toml::Value)>,
+        /// Keep watching the dotfiles and redeploy the ones that change
+        #[arg(short, long, conflicts_with_all = ["interactive", "git_ref", "format"])]
+        watch: bool,
+        /// Number of templates processed in parallel (the number of cores by default)
+        #[arg(short, long, value_name = "N")]
+        jobs: Option<NonZeroUsize>,
+    },
+    /// Remove deployed dotfiles from their locations
+    Undeploy {
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        force: bool,
+        /// Print the files that would be removed, without removing them
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Directory the dotfiles were deployed into (instead of the host's configured one or home)
+        #[arg(long, value_name = "DIR")]
+        target: Option<String>,
+        /// List the files in the deployed files' directories that weren't deployed, and
+        /// would remain, without removing anything
+        #[arg(short, long, conflicts_with_all = ["force", "dry_run"])]
+        reverse: bool,
+    },
+    /// Remove deployed files whose dotfiles no longer exist
+    Clean {
+        /// Print the files that would be removed, without removing them
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Also remove files that were changed after they were deployed
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Show which deployed dotfiles differ from what would be deployed
+    Status {
+        /// Directory the dotfiles were deployed into (instead of the host's configured one or home)
+        #[arg(long, value_name = "DIR")]
+        target: Option<String>,
+    },
+    /// List the packages or dotfiles managed by `dot`
+    List {
+        what: Listing,
+        /// Directory the dotfiles would be deployed into (instead of the host's configured one or home)
+        #[arg(long, value_name = "DIR")]
+        target: Option<String>,
+        /// Output format
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+    },
+    /// Create a new dotfiles directory and point the config at it
+    Init {
+        /// Where to create the dotfiles (`~/.dotfiles` by default)
+        path: Option<String>,
+        /// Use the directory even if it's not empty (existing files are kept)
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Check that the config, the dotfiles and the tools `dot` uses are in order
+    Doctor,
+    /// Compare two exported deploy states (e.g. from different machines)
+    Compare { state_a: PathBuf, state_b: PathBuf },
+    /// Run a task defined in `..toml`
+    Run {
+        #[arg(required_unless_present = "list")]
+        name: Option<String>,
+        /// List available tasks
+        #[arg(short, long)]
+        list: bool,
     },
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Config {
-    paths: Paths,
-}
+fn main() -> Result<(), DotteryError> {
+    let mut args = Args::parse();
 
-impl Default for Config {
-    fn default() -> Self {
-        let mut home = home_dir().unwrap();
-        home.push(".dotfiles");
+    args.config = args
+        .config
+        .or_else(|| std::env::var_os("DOTTERY_CONFIG").map(PathBuf::from));
 
-        let dotfiles_path = home.to_str().unwrap();
+    logging::set_level(Level::from_flags(args.verbose, args.quiet));
 
-        Self {
-            paths: Paths {
-                dotfiles_path: dotfiles_path.into(),
-            },
-        }
+    // NOTE: Neither the config nor the dotfiles have to exist yet
+    if let Command::Init { ref path, force } = args.command {
+        return Ok(init(path.as_deref(), args.config.clone(), force)?);
     }
-}
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Paths {
-    dotfiles_path: String,
-}
+    // NOTE: Problems with the config or the dotfiles are reported as failed checks
+    if let Command::Doctor = args.command {
+        return Ok(doctor(
+            args.config.clone(),
+            !args.no_canonicalize,
+            args.profile.as_deref(),
+        )?);
+    }
 
-#[derive(Clone, Debug, Deserialize)]
-struct Dotfiles {
-    packages: Vec<Package>,
-    dependencies: Option<Dependencies>,
-}
+    let mut config = read_config(args.config.clone(), !args.no_canonicalize)?;
 
-#[derive(Clone, Debug, Deserialize)]
-struct Package {
-    name: String,
-    from_aur: bool,
-}
+    dotfiles_from_env(&mut config, !args.no_canonicalize)?;
 
-#[derive(Clone, Debug, Deserialize)]
-struct Dependencies {
-    required: Option<Vec<Package>>,
-    optional: Option<Vec<Package>>,
-}
+    validate_dotfiles_dir(&config, &config_path(args.config.clone()))?;
 
-impl Package {
-    fn name(&self) -> &str {
-        self.name.as_str()
-    }
+    let theme_name = std::env::var("DOTTERY_THEME").unwrap_or(config.logging.theme.clone());
 
-    fn from_aur(&self) -> bool {
-        self.from_aur
+    match Theme::from_name(&theme_name) {
+        Some(theme) => logging::set_theme(theme),
+        None => log_error(&format!("unknown theme: `{theme_name}`")),
     }
-}
-
-fn main() -> io::Result<()> {
-    let config = read_config()?;
-
-    std::env::set_current_dir(&config.paths.dotfiles_path).expect("dotfiles directory not found");
 
-    let mut settings: toml::Value = std::fs::read_to_string("..toml")
-        .expect("`..toml` not found in dotfiles directory")
-        .pipe(|s| toml::from_str(&s))
-        .expect("failed to parse dotfiles configuration");
+    // Paths given as arguments are relative to the original working directory
+    let cwd = std::env::current_dir()?;
 
-    let dotfiles = settings
-        .as_table_mut()
-        .map(|ss| {
-            let dottery = ss
-                .remove("dottery")
-                .expect("failed to get section `dottery`");
-
-            Dotfiles::deserialize(dottery)
-        })
-        .expect("failed to parse config file") // HACK: Unwrapping `Option<Result<_>>`
-        .expect("failed to parse config file");
+    std::env::set_current_dir(&config.paths.dotfiles_path)?;
 
-    let args = Args::parse();
+    let (settings, dotfiles) = read_settings(
+        ".",
+        &config.paths.manifest_file,
+        config.files.include.as_deref(),
+        args.profile.as_deref(),
+    )?;
 
     match args.command {
         Command::Install {
             packages: packages_to_install,
+            strict,
+            noconfirm,
+            group,
+            skip,
+            aur_only,
+            no_aur,
         } => {
-            let (cmd, packages) =
-                filter_packages(dotfiles.packages.iter(), packages_to_install.as_ref());
-
-            install_pkgs(cmd, packages.into_iter())
-                .expect(&format!("failed to spawn process `{cmd}`"));
+            let manager = package_manager()?;
+            let profile = args
+                .profile
+                .as_deref()
+                .and_then(|p| dotfiles.profiles.get(p));
+            let packages = filter_packages(
+                manager,
+                dotfiles
+                    .packages
+                    .iter()
+                    .filter(|pkg| profile.is_none_or(|p| p.selects(pkg)))
+                    .filter(|pkg| group.as_deref().is_none_or(|g| pkg.group() == Some(g)))
+                    .filter(|pkg| !skip.iter().any(|s| s == pkg.name()))
+                    .filter(|pkg| (!aur_only || pkg.from_aur()) && (!no_aur || !pkg.from_aur())),
+                packages_to_install.as_ref(),
+            );
 
-            // TODO: Perform post-installation
+            // NOTE: Returning early would skip the `--fail-on-warning` check
+            if aur_only && !manager.supports_aur() {
+                log_warning(&format!("`{manager}` can't install packages from the AUR"));
+            } else if let (Some(g), true) = (&group, packages.is_empty()) {
+                log_warning(&format!("no packages to install in group `{g}`"));
+            } else if noconfirm || confirm_install(manager, &packages)? {
+                install_packages(&config, &dotfiles, manager, &packages, noconfirm, strict);
+            }
         }
-        Command::Sync => {
-            run_cmd!(git pull).pipe(log_on_err);
+        Command::Sync {
+            push,
+            message,
+            stash,
+            remote,
+            branch,
+        } => {
+            let mut config = config;
+            config.sync.remote = remote.or(config.sync.remote);
+            config.sync.branch = branch.or(config.sync.branch);
 
-            run_cmd! {
-                git submodule init;
-                git submodule sync;
-                git submodule update;
-            }
-            .pipe(log_on_err);
+            sync(&config, push.then_some(message.as_str()), stash)?
         }
         Command::Deploy {
             dotfiles: dotfiles_to_deploy,
             template: template_only,
             raw: raw_only,
+            only_new,
+            interactive,
+            dry_run,
+            link,
+            backup,
+            diff,
+            force,
+            no_sass,
+            git_ref,
+            target,
+            strict,
+            format,
+            set,
+            watch,
+            jobs,
         } => {
-            let home = home_dir().unwrap();
-            let home_str = home.to_str().unwrap();
+            if let Format::Json = format {
+                logging::set_level(Level::Quiet);
+            }
+
+            let mut config = config;
+            let mut settings = settings;
+            let mut dotfiles = dotfiles;
+
+            if let Some(r) = git_ref {
+                match export_ref(&r) {
+                    Ok(dir) => {
+                        config.paths.dotfiles_path = dir.to_string_lossy().to_string();
+                        (settings, dotfiles) = read_settings(
+                            &dir,
+                            &config.paths.manifest_file,
+                            config.files.include.as_deref(),
+                            args.profile.as_deref(),
+                        )
+                        .tap_err(|_| remove_snapshot())?;
+                    }
+                    Err(e) => {
+                        return Err(DotteryError::Git(format!(
+                            "failed to read git ref `{r}`: {e}"
+                        )));
+                    }
+                }
+            }
+
+            dotfiles.templating.strict |= strict;
+            override_settings(&mut settings, &set).unwrap_or_else(|e| {
+                remove_snapshot();
+                fail(&e)
+            });
+
+            let target_root = target_root(
+                &dotfiles,
+                target.map(|t| cwd.join(t).to_string_lossy().to_string()),
+            );
+            let target_root = target_root.as_str();
+
+            let options = DeployOptions {
+                only_new,
+                interactive,
+                dry_run,
+                link,
+                backup,
+                diff,
+                force,
+                no_sass,
+                jobs,
+            };
+
+            if let Some(ref cmd) = config.hooks.pre_deploy {
+                if !run_hook("pre-deploy", cmd, dry_run, format) && config.hooks.abort_on_failure {
+                    remove_snapshot();
+                    fail("aborting the deploy");
+                }
+            }
+
+            let mut results = DeployResults::default();
 
             if !template_only {
                 log_msg("Copying raw files");
 
-                copy_raw(&config, home_str);
+                results.extend(copy_raw(
+                    dotfiles_to_deploy.as_deref(),
+                    &config,
+                    &dotfiles,
+                    target_root,
+                    options,
+                ));
             }
 
             if !raw_only {
                 log_msg("Processing template files");
 
-                process_templates(dotfiles_to_deploy, settings, &config, home_str).pipe(log_on_err);
+                match process_templates(
+                    dotfiles_to_deploy.clone(),
+                    settings.clone(),
+                    &config,
+                    &dotfiles,
+                    target_root,
+                    options,
+                ) {
+                    Ok(rs) => results.extend(rs),
+                    Err(e) => log_error(&format!("{e}")),
+                }
+            }
+
+            finish_deploy(&config, target_root, &results, dry_run, format);
+
+            remove_snapshot();
+            report_deploy(&results, dry_run, format);
+
+            if watch {
+                let dotfiles_dir = PathBuf::from(&config.paths.dotfiles_path);
+
+                watch::watch(&dotfiles_dir, |changed| {
+                    // NOTE: Templates may use any variable, so all of them are redeployed
+                    // if a manifest changes
+                    let manifest_changed = changed.iter().any(|p| {
+                        p.parent() == Some(&dotfiles_dir)
+                            && p.extension().is_some_and(|e| e == "toml")
+                    });
+
+                    if manifest_changed {
+                        match read_settings(
+                            ".",
+                            &config.paths.manifest_file,
+                            config.files.include.as_deref(),
+                            args.profile.as_deref(),
+                        ) {
+                            Ok(s) => {
+                                (settings, dotfiles) = s;
+                                dotfiles.templating.strict |= strict;
+                                override_settings(&mut settings, &set).pipe(log_on_err);
+                            }
+                            Err(e) => return log_error(&e.to_string()),
+                        }
+                    }
+
+                    let changed_in = |dir: &str| -> Vec<String> {
+                        let dir = dotfiles_dir.join(dir);
+
+                        changed
+                            .iter()
+                            .filter_map(|p| p.strip_prefix(&dir).ok())
+                            .filter(|p| {
+                                dotfiles_to_deploy.as_ref().is_none_or(|ds| {
+                                    p.iter()
+                                        .any(|c| ds.iter().any(|d| *d == c.to_string_lossy()))
+                                })
+                            })
+                            .filter_map(|p| p.file_name())
+                            .map(|n| n.to_string_lossy().to_string())
+                            .collect()
+                    };
+
+                    let raw = changed_in("raw");
+                    let templates = changed_in("template");
+
+                    // NOTE: Partials aren't deployed themselves, so any template may need
+                    // redeploying if one changes
+                    let partials = dotfiles_dir
+                        .join("template")
+                        .join(dotfiles.templating.partials());
+                    let partial_changed = changed.iter().any(|p| p.starts_with(&partials));
+                    let redeploy_all = manifest_changed || partial_changed;
+
+                    let mut results = DeployResults::default();
+
+                    if !template_only && !raw.is_empty() {
+                        results.extend(copy_raw(
+                            Some(&raw),
+                            &config,
+                            &dotfiles,
+                            target_root,
+                            options,
+                        ));
+                    }
+
+                    if !raw_only && (redeploy_all || !templates.is_empty()) {
+                        let to_deploy = match redeploy_all {
+                            true => dotfiles_to_deploy.clone(),
+                            false => Some(templates),
+                        };
+
+                        match process_templates(
+                            to_deploy,
+                            settings.clone(),
+                            &config,
+                            &dotfiles,
+                            target_root,
+                            options,
+                        ) {
+                            Ok(rs) => results.extend(rs),
+                            Err(e) => log_error(&format!("{e}")),
+                        }
+                    }
+
+                    if !results.actions.is_empty() {
+                        finish_deploy(&config, target_root, &results, dry_run, format);
+                        report_deploy(&results, dry_run, format);
+                    }
+                })
+                .map_err(io::Error::other)?;
+            } else if results.failed().next().is_some() {
+                process::exit(1);
+            } else if results.skipped().next().is_some() {
+                process::exit(2);
+            }
+        }
+        Command::Undeploy {
+            force,
+            dry_run,
+            target,
+            reverse,
+        } => {
+            let target_root = target_root(
+                &dotfiles,
+                target.map(|t| cwd.join(t).to_string_lossy().to_string()),
+            );
+            let state_file = state_path(&config);
+            let mut state = DeployState::read_or_default(&state_file)?;
+
+            // NOTE: Without a record of the deployed files, they're guessed from the dotfiles
+            let recorded = state.roots.contains_key(&target_root);
+            let targets = if recorded {
+                state.targets(&target_root)
+            } else {
+                deployed_targets(&config, &dotfiles, &target_root)
+            };
+
+            if targets.is_empty() {
+                log_msg("No deployed files found");
+            } else if reverse {
+                let remaining = unmanaged_files(&targets);
+
+                for (dir, entries) in &remaining {
+                    log_msg(&dir.display().to_string());
+                    entries.iter().for_each(|e| println!("{}", e.display()));
+                }
+
+                log_msg(&format!(
+                    "{} unmanaged file(s) would remain in {} directories",
+                    remaining.values().map(Vec::len).sum::<usize>(),
+                    remaining.len()
+                ));
+            } else {
+                targets.iter().for_each(|t| println!("{}", t.display()));
+
+                let confirmed = dry_run
+                    || force
+                    || prompt(
+                        &format!("Remove {} file(s)", targets.len()),
+                        &[('y', "yes"), ('n', "no")],
+                    )? == 'y';
+
+                if !dry_run && confirmed {
+                    targets.iter().for_each(|t| match std::fs::remove_file(t) {
+                        Ok(()) => state.forget(&target_root, t),
+                        Err(e) => log_error(&format!("failed to remove `{}`: {e}", t.display())),
+                    });
+
+                    if recorded {
+                        state.write(&state_file)?;
+                    }
+                }
+            }
+        }
+        Command::Clean { dry_run, force } => clean(&config, dry_run, force)?,
+        Command::Status { target } => {
+            let target_root = target_root(
+                &dotfiles,
+                target.map(|t| cwd.join(t).to_string_lossy().to_string()),
+            );
+            let statuses = deploy_status(settings, &config, &dotfiles, &target_root);
+
+            statuses
+                .iter()
+                .for_each(|(path, status)| log_status(*status, path));
+
+            let identical = statuses
+                .iter()
+                .filter(|(_, status)| *status == FileStatus::Identical)
+                .count();
+
+            log_msg(&format!(
+                "{identical} of {} file(s) identical",
+                statuses.len()
+            ));
+        }
+        Command::List {
+            what: Listing::Packages,
+            format,
+            ..
+        } => {
+            let profile = args
+                .profile
+                .as_deref()
+                .and_then(|p| dotfiles.profiles.get(p));
+            let packages: Vec<_> = dotfiles
+                .packages
+                .iter()
+                .filter(|pkg| profile.is_none_or(|p| p.selects(pkg)))
+                .collect();
+
+            match format {
+                Format::Json => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&packages)
+                        .expect("packages should be serializable")
+                ),
+                Format::Text => packages.iter().for_each(|pkg| {
+                    let aur = if pkg.from_aur() { " (AUR)" } else { "" };
+
+                    println!("{}{aur}", pkg.name());
+                }),
+            }
+        }
+        Command::List {
+            what: Listing::Dotfiles,
+            target,
+            format,
+        } => {
+            let target_root = target_root(
+                &dotfiles,
+                target.map(|t| cwd.join(t).to_string_lossy().to_string()),
+            );
+            let files = managed_files(&config, &dotfiles, &target_root);
+
+            match format {
+                Format::Json => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&files).expect("files should be serializable")
+                ),
+                Format::Text => files
+                    .iter()
+                    .for_each(|f| println!("{} -> {}", f.source.display(), f.target.display())),
+            }
+        }
+        Command::Compare { state_a, state_b } => {
+            let read = |path: &PathBuf| {
+                DeployState::read(cwd.join(path)).unwrap_or_else(|e| {
+                    fail(&format!("failed to read state `{}`: {e}", path.display()));
+                })
+            };
+
+            let (a, b) = (read(&state_a), read(&state_b));
+            let statuses = a.compare(&b);
+
+            statuses
+                .iter()
+                .filter(|(_, status)| *status != FileStatus::Identical)
+                .for_each(|(path, status)| log_status(*status, path));
+
+            let identical = statuses
+                .iter()
+                .filter(|(_, status)| *status == FileStatus::Identical)
+                .count();
+
+            log_msg(&format!(
+                "{identical} of {} file(s) identical",
+                statuses.len()
+            ));
+        }
+        Command::Run { name, list } => {
+            let tasks = dotfiles.tasks.unwrap_or_default();
+
+            if list {
+                log_msg("Tasks");
+
+                let mut names: Vec<_> = tasks.keys().collect();
+                names.sort_unstable();
+
+                names
+                    .into_iter()
+                    .for_each(|name| match tasks[name].description {
+                        Some(ref d) => println!("{name} - {d}"),
+                        None => println!("{name}"),
+                    });
+            } else if let Some(name) = name {
+                let Some(task) = tasks.get(&name) else {
+                    fail(&format!("no task named `{name}`"));
+                };
+
+                log_msg(&format!("Running `{name}`"));
+
+                match process::Command::new("sh").args(["-c", &task.cmd]).status() {
+                    Ok(status) if status.success() => (),
+                    Ok(status) => {
+                        log_error(&format!("task `{name}` failed ({status})"));
+                        process::exit(status.code().unwrap_or(1));
+                    }
+                    Err(e) => {
+                        fail(&format!("failed to run task `{name}`: {e}"));
+                    }
+                }
             }
         }
         Command::Locate => {
             log_msg("Dotfiles directory");
             println!("{}", config.paths.dotfiles_path);
         }
+        Command::Init { .. } | Command::Doctor => {
+            unreachable!("handled before reading the config")
+        }
         Command::InstallDeps {
             required: required_only,
             optional: optional_only,
+            dry_run,
+            noconfirm,
         } => match dotfiles.dependencies {
             None => (),
             Some(ds) => {
-                if !optional_only {
-                    if let Some(ps) = ds.required {
-                        let (cmd, packages) = filter_packages(ps.iter(), None);
+                let manager = package_manager()?;
+                let install = |packages: Vec<&str>| match install_pkgs(
+                    manager,
+                    &config.install_args(manager),
+                    noconfirm,
+                    config.batch_size(manager),
+                    packages.into_iter(),
+                ) {
+                    Ok(status) if status.success() => true,
+                    Ok(status) => {
+                        log_error(&format!("`{manager}` failed ({status})"));
+                        false
+                    }
+                    Err(e) => {
+                        log_error(&format!("failed to run `{manager}`: {e}"));
+                        false
+                    }
+                };
+                let mut installed = true;
+                // NOTE: By default, required dependencies are simply installed, while
+                // optional ones are chosen one by one
+                let choose_each = !required_only && !optional_only && !noconfirm;
 
-                        install_pkgs(cmd, packages.into_iter()).pipe(log_on_err);
+                if !optional_only {
+                    if let Some(ref ps) = ds.required {
+                        let packages = filter_packages(manager, ps.iter(), None);
+
+                        if dry_run {
+                            log_msg(&format!("Required dependencies (via `{manager}`)"));
+                            packages.iter().for_each(|p| println!("{p}"));
+                        } else if noconfirm || choose_each || confirm_install(manager, &packages)? {
+                            installed &= install(packages);
+                        }
                     };
                 }
 
                 if !required_only {
                     if let Some(ps) = ds.optional {
-                        let (cmd, packages) = filter_packages(ps.iter(), None);
-
-                        install_pkgs(cmd, packages.into_iter()).pipe(log_on_err);
+                        // NOTE: Required dependencies may also be listed as optional
+                        let required: Vec<&str> = match ds.required {
+                            Some(ref rs) if !optional_only => {
+                                rs.iter().map(Package::name).collect()
+                            }
+                            _ => Vec::new(),
+                        };
+                        let packages = filter_packages(
+                            manager,
+                            ps.iter().filter(|p| !required.contains(&p.name())),
+                            None,
+                        );
+
+                        if dry_run {
+                            log_msg(&format!("Optional dependencies (via `{manager}`)"));
+                            packages.iter().for_each(|p| println!("{p}"));
+                        } else if choose_each {
+                            let chosen = choose_optional(packages)?;
+
+                            if !chosen.is_empty() {
+                                installed &= install(chosen);
+                            }
+                        } else if noconfirm || confirm_install(manager, &packages)? {
+                            installed &= install(packages);
+                        }
                     }
                 }
+
+                if !installed {
+                    process::exit(1);
+                }
             }
         },
     }
 
+    if args.fail_on_warning && logging::issue_count() > 0 {
+        process::exit(1);
+    }
+
     Ok(())
 }
 
-fn read_config() -> io::Result<Config> {
-    let config_file = config_dir()
-        .unwrap()
-        .tap_mut(|cf| cf.push(CONFIG_DIR))
-        .tap_mut(|cf| cf.push(CONFIG_FILE));
+/// Installs the packages, then runs the post-install hooks of the installed ones and
+/// verifies them
+fn install_packages(
+    config: &Config,
+    dotfiles: &Dotfiles,
+    manager: PackageManager,
+    packages: &[&str],
+    noconfirm: bool,
+    strict: bool,
+) {
+    let status = install_pkgs(
+        manager,
+        &config.install_args(manager),
+        noconfirm,
+        config.batch_size(manager),
+        packages.iter().copied(),
+    )
+    .map_err(|e| log_error(&format!("failed to run `{manager}`: {e}")));
+
+    let installed = || {
+        dotfiles
+            .packages
+            .iter()
+            .filter(|pkg| packages.contains(&pkg.install_name(manager)))
+    };
+
+    match status {
+        Ok(status) if status.success() => run_post_install(installed()),
+        Ok(status) => {
+            log_error(&format!(
+                "`{manager}` failed ({status}), skipping post-install hooks and verification"
+            ));
+            process::exit(1);
+        }
+        Err(()) => {
+            log_warning("skipping post-install hooks and verification");
+            process::exit(1);
+        }
+    }
 
-    match std::fs::read_to_string(config_file.clone()) {
-        Err(e) if e.kind() == io::ErrorKind::NotFound => {
-            std::fs::create_dir_all(config_file.clone().parent().unwrap())?;
+    let failed = verify_pkgs(installed());
+
+    if strict && failed > 0 {
+        fail(&format!("{failed} package(s) failed verification"));
+    }
+}
+
+/// Lists the packages that would be installed and asks whether to proceed
+fn confirm_install(manager: PackageManager, packages: &[&str]) -> io::Result<bool> {
+    if packages.is_empty() {
+        return Ok(true);
+    }
 
-            let config_default = Config::default();
+    packages.iter().for_each(|p| println!("{p}"));
 
-            toml::to_string(&config_default)
-                .expect("failed to convert config to `toml`")
-                .pipe(|s| std::fs::write(config_file, s))
-                .expect("failed to write config file");
+    let choice = prompt(
+        &format!("Install {} package(s) via `{manager}`", packages.len()),
+        &[('y', "yes"), ('n', "no")],
+    )?;
+
+    Ok(choice == 'y')
+}
 
-            Ok(config_default)
+/// Asks whether to install each of the optional dependencies
+fn choose_optional(packages: Vec<&str>) -> io::Result<Vec<&str>> {
+    let mut chosen = Vec::new();
+
+    for p in packages {
+        if prompt(
+            &format!("Install optional dependency `{p}`"),
+            &[('y', "yes"), ('n', "no")],
+        )? == 'y'
+        {
+            chosen.push(p);
         }
-        Err(e) => {
-            log_error(&format!("failed to read config file: {e}"));
+    }
+
+    Ok(chosen)
+}
 
-            Ok(Config::default())
+/// Contents of a new `..toml`
+const STARTER_MANIFEST: &str = r##"# Template substitutions, e.g.:
+# [colors]
+# background = "#000000"
+
+[dottery]
+packages = []
+"##;
+
+/// Contents of a new `.personal.toml`
+const STARTER_PERSONAL: &str = "# Machine- or user-specific settings, merged over `..toml`\n";
+
+/// Scaffolds a dotfiles directory and points the config at it
+fn init(path: Option<&str>, config_file: Option<PathBuf>, force: bool) -> io::Result<()> {
+    let dir = path
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Config::default().paths.dotfiles_path.into())
+        .pipe(|d| std::env::current_dir().map(|cwd| cwd.join(d)))?;
+
+    if !force
+        && dir
+            .read_dir()
+            .is_ok_and(|mut entries| entries.next().is_some())
+    {
+        fail(&format!(
+            "`{}` is not empty, pass `--force` to use it anyway",
+            dir.display()
+        ));
+    }
+
+    std::fs::create_dir_all(dir.join("raw"))?;
+    std::fs::create_dir_all(dir.join("template"))?;
+
+    [
+        ("..toml", STARTER_MANIFEST),
+        (".personal.toml", STARTER_PERSONAL),
+    ]
+    .into_iter()
+    .try_for_each(|(name, contents)| {
+        let file = dir.join(name);
+
+        if file.exists() {
+            log_msg(&format!("Keeping existing `{name}`"));
+            Ok(())
+        } else {
+            std::fs::write(file, contents)
         }
-        Ok(s) => Ok(toml::from_str(&s).expect("failed to parse config file")),
-    }
-    .tap_ok_mut(|c| {
-        c.paths.dotfiles_path = canonicalize(&c.paths.dotfiles_path)
-            .expect(&format!(
-                "dotfiles path not found: `{}`",
-                &c.paths.dotfiles_path
-            ))
-            .to_str()
-            .unwrap()
-            .to_string()
-    })
+    })?;
+
+    config::set_dotfiles_path(&config_path(config_file), &dir.to_string_lossy())?;
+
+    log_msg(&format!("Initialized dotfiles in `{}`", dir.display()));
+
+    Ok(())
 }
 
-fn install_pkgs<'a>(cmd: &str, packages: impl Iterator<Item = &'a str>) -> io::Result<ExitStatus> {
-    let mut args = vec!["-S", "--needed"];
+/// Detects the package manager to use
+fn package_manager() -> Result<PackageManager, DotteryError> {
+    PackageManager::detect().ok_or_else(|| {
+        DotteryError::NoPackageManager(PackageManager::ALL.map(|m| format!("`{m}`")).join(", "))
+    })
+}
 
-    args.extend(packages);
+/// Overrides the dotfiles path with the `DOTTERY_DOTFILES` environment variable, if it's
+/// set. Relative paths are relative to the working directory.
+fn dotfiles_from_env(config: &mut Config, canonicalize: bool) -> io::Result<()> {
+    let Some(dir) = std::env::var_os("DOTTERY_DOTFILES") else {
+        return Ok(());
+    };
+    let dir = std::env::current_dir()?.join(config::expand_str(&dir.to_string_lossy()));
+
+    // NOTE: A missing directory is reported by `validate_dotfiles_dir`
+    let dir = if canonicalize && config.paths.canonicalize {
+        std::fs::canonicalize(&dir).unwrap_or(dir)
+    } else {
+        dir
+    };
 
-    if args.len() == 2 {
-        // HACK: Should return signify that there's no packages to install
-        return Ok(ExitStatus::default());
-    }
+    config.paths.dotfiles_path = dir.to_string_lossy().to_string();
 
-    process::Command::new(cmd)
-        .args(args)
-        .stdin(Stdio::inherit())
-        .spawn()
-        .map(|mut c| c.wait())
-        .unwrap_or_else(|e| Err(e))
+    Ok(())
 }
 
-fn copy_raw(config: &Config, home_str: &str) {
-    let dir = format!("{}/raw/", config.paths.dotfiles_path);
-    let files = WalkDir::new(dir);
+/// Prints a checklist of what `dot` needs to work, exiting with code `1` if anything
+/// required is missing
+fn doctor(
+    config_file: Option<PathBuf>,
+    canonicalize: bool,
+    profile: Option<&str>,
+) -> io::Result<()> {
+    let path = config_path(config_file.clone());
+    let mut failed = 0;
+    let mut check = |passed: bool, msg: String| {
+        if !passed {
+            failed += 1;
+        }
 
-    files
-        .into_iter()
-        .filter_map(|r| match r {
-            Ok(d) => d.file_type().is_file().then_some(d),
+        log_check(passed, &msg);
+    };
+
+    // NOTE: `read_config` would create a missing config
+    let mut config = if !path.exists() {
+        log_check(
+            false,
+            &format!(
+                "Config `{}` doesn't exist, using the defaults",
+                path.display()
+            ),
+        );
+        Config::default()
+    } else {
+        match read_config(config_file, canonicalize) {
+            Ok(c) => {
+                check(true, format!("Config `{}` is valid", path.display()));
+                c
+            }
             Err(e) => {
-                log_error(&format!("failed to read file: {e}"));
-                None
+                check(false, e.to_string());
+                process::exit(1);
             }
-        })
-        .for_each(|f| {
-            let path_str = f
-                .path()
-                .to_string_lossy()
-                .to_string()
-                .tap(|p| println!("{p}"));
-            let target_path =
-                path_str.replace(&format!("{}/raw", config.paths.dotfiles_path), home_str);
-            let parent_dir = Path::new(&target_path).parent().unwrap();
-
-            if !parent_dir.exists() {
-                std::fs::create_dir_all(&parent_dir).pipe(log_on_err);
+        }
+    };
+
+    dotfiles_from_env(&mut config, canonicalize)?;
+
+    match validate_dotfiles_dir(&config, &path) {
+        Ok(()) => {
+            let dir = &config.paths.dotfiles_path;
+
+            check(true, format!("Dotfiles directory `{dir}` exists"));
+
+            match read_settings(
+                dir,
+                &config.paths.manifest_file,
+                config.files.include.as_deref(),
+                profile,
+            ) {
+                Ok(_) => check(
+                    true,
+                    format!("Manifest `{}` is valid", config.paths.manifest_file),
+                ),
+                Err(e) => check(false, e.to_string()),
             }
+        }
+        Err(e) => check(false, e.to_string()),
+    }
+
+    match PackageManager::detect() {
+        Some(m) => check(true, format!("Package manager `{m}` is installed")),
+        None => check(false, "No supported package manager is installed".into()),
+    }
+
+    let sync_tool = match config.sync.backend {
+        SyncBackend::Git => Some("git"),
+        SyncBackend::Rsync => Some("rsync"),
+        SyncBackend::None => None,
+    };
+
+    if let Some(tool) = sync_tool {
+        check(
+            command_exists(tool),
+            format!("`{tool}` is installed (needed by `sync`)"),
+        );
+    }
 
-            std::fs::copy(&path_str, &target_path).pipe(log_on_err)
-        });
+    // NOTE: Without a compiler, SASS templates are only deployed as they are
+    match sass_compiler() {
+        Some(c) => log_check(true, &format!("SASS compiler `{c}` is installed")),
+        None => log_check(
+            false,
+            "No SASS compiler is installed (optional, needed to compile SASS templates)",
+        ),
+    }
+
+    if failed > 0 {
+        process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Runs a deploy hook (only logging it on a dry run), returning whether it succeeded. With
+/// JSON output, the hook's output goes to stderr, so that stdout stays valid JSON.
+fn run_hook(name: &str, cmd: &str, dry_run: bool, format: Format) -> bool {
+    if dry_run {
+        log_msg(&format!("Would run {name} hook `{cmd}`"));
+        return true;
+    }
+
+    log_msg(&format!("Running {name} hook"));
+
+    let mut command = process::Command::new("sh");
+    command.arg("-c").arg(cmd);
+
+    if let Format::Json = format {
+        command.stdout(io::stderr());
+    }
+
+    match command.status() {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            log_error(&format!("{name} hook failed ({status})"));
+            false
+        }
+        Err(e) => {
+            log_error(&format!("{name} hook failed: {e}"));
+            false
+        }
+    }
 }
 
-fn process_templates(
-    to_deploy: Option<Vec<String>>,
-    settings: toml::Value,
+/// Parses a `key=value` argument, where the value is TOML (or a string, if it's not valid
+/// TOML)
+fn parse_assignment(arg: &str) -> Result<(String, toml::Value), String> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected `KEY=VALUE`, got `{arg}`"))?;
+
+    let value = toml::from_str::<toml::Table>(&format!("value = {value}"))
+        .ok()
+        .and_then(|mut t| t.remove("value"))
+        .unwrap_or_else(|| value.into());
+
+    Ok((key.into(), value))
+}
+
+/// Applies the values given with `--set` to the settings
+fn override_settings(
+    settings: &mut toml::Value,
+    values: &[(String, toml::Value)],
+) -> Result<(), String> {
+    values
+        .iter()
+        .try_for_each(|(k, v)| set_setting(settings, k, v.clone()))
+}
+
+/// Records the deployed files (unless it's a dry run) and runs the hooks that follow a
+/// deploy
+fn finish_deploy(
     config: &Config,
-    home_str: &str,
-) -> io::Result<()> {
-    let dir = format!("{}/template/", config.paths.dotfiles_path);
-    let files = WalkDir::new(dir);
-
-    let env = Environment::new();
-
-    files
-        .into_iter()
-        .filter_entry(|e| {
-            e.file_type().is_dir()
-                || if let Some(ref ds) = to_deploy {
-                    e.clone()
-                        .into_path()
-                        .components()
-                        .find(|c| {
-                            if let Component::Normal(d) = c {
-                                ds.contains(&d.to_string_lossy().to_string())
-                            } else {
-                                false
-                            }
-                        })
-                        .is_some()
-                } else {
-                    true
-                }
-        })
-        .filter_map(|r| match r {
-            Ok(d) => d.file_type().is_file().then_some(d),
-            Err(e) => {
-                log_error(&format!("failed to read file: {e}"));
-                None
-            }
-        })
-        .map(|f| {
-            let path = f.path();
-            let path_str = path.to_str().unwrap();
-            let file = std::fs::File::open(path)?;
+    target_root: &str,
+    results: &DeployResults,
+    dry_run: bool,
+    format: Format,
+) {
+    if !dry_run {
+        record_deploy(config, target_root, results);
+    }
 
-            let mut buf = BufReader::new(file);
-            let mut contents = String::new();
+    run_change_hooks(config, results, dry_run, format);
 
-            match bindet::detect(&mut buf)? {
-                Some(_) => return Ok(()),
-                None => (),
-            };
+    if let Some(ref cmd) = config.hooks.post_deploy {
+        run_hook("post-deploy", cmd, dry_run, format);
+    }
+}
 
-            buf.rewind()?;
-            buf.read_to_string(&mut contents)?;
+/// Prints the outcome of a deploy: the failed and skipped files along with a summary, or
+/// all actions as JSON
+fn report_deploy(results: &DeployResults, dry_run: bool, format: Format) {
+    if let Format::Json = format {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&results.actions).expect("actions should be serializable")
+        );
 
-            println!("{path_str}");
+        return;
+    }
 
-            let tmpl = match env.template_from_str(&contents) {
-                Ok(t) => t,
-                Err(e) => {
-                    log_error(&format!("{e}"));
-                    return Ok(());
-                }
-            };
+    let failed: Vec<_> = results.failed().collect();
+    let skipped: Vec<_> = results.skipped().collect();
+
+    if !failed.is_empty() {
+        log_error(&format!("{} file(s) failed to deploy", failed.len()));
+
+        failed.iter().for_each(|p| println!("{}", p.display()));
+    }
+
+    if !skipped.is_empty() {
+        log_msg(&format!("Skipped {} existing file(s)", skipped.len()));
+
+        skipped.iter().for_each(|p| println!("{}", p.display()));
+    }
+
+    let verb = if dry_run { "Would deploy" } else { "Deployed" };
+
+    log_msg(&format!("{verb} {}", results.summary()));
+}
+
+/// Runs the hooks of the paths under which files were written, each command at most once
+fn run_change_hooks(config: &Config, results: &DeployResults, dry_run: bool, format: Format) {
+    let written: Vec<_> = results
+        .written()
+        .filter_map(|p| p.strip_prefix(&config.paths.dotfiles_path).ok())
+        .collect();
+
+    let mut hooks: Vec<_> = config
+        .hooks
+        .on_change
+        .iter()
+        .filter(|(prefix, _)| written.iter().any(|p| p.starts_with(prefix)))
+        .collect();
+    hooks.sort();
+
+    let mut run = HashSet::new();
+
+    for (prefix, cmd) in hooks {
+        if run.insert(cmd) {
+            run_hook(&format!("`{prefix}`"), cmd, dry_run, format);
+        }
+    }
+}
+
+/// Records the deployed files in the state file
+fn record_deploy(config: &Config, target_root: &str, results: &DeployResults) {
+    let path = state_path(config);
+
+    let result = DeployState::read_or_default(&path).and_then(|mut state| {
+        state.record(target_root, &results.actions, &config.paths.dotfiles_path);
+        state.write(&path)
+    });
 
-            let output = match tmpl.render(&settings) {
-                Ok(o) => o,
+    if let Err(e) = result {
+        log_error(&format!("failed to record deployed files: {e}"));
+    }
+}
+
+/// Removes the recorded deployed files whose dotfiles no longer exist, and forgets them.
+/// Files changed since they were deployed are kept, unless `force` is set.
+fn clean(config: &Config, dry_run: bool, force: bool) -> io::Result<()> {
+    let state_file = state_path(config);
+    let dotfiles_path = &config.paths.dotfiles_path;
+
+    let mut state = match DeployState::read(&state_file) {
+        Ok(state) => state,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            log_msg("No deployed files recorded");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    // NOTE: Files deployed into every directory (e.g. with `--target`) are cleaned up
+    let orphans: Vec<(String, String, String)> = state
+        .roots
+        .iter()
+        .flat_map(|(root, files)| files.iter().map(move |(target, file)| (root, target, file)))
+        .filter(|(_, _, file)| !Path::new(dotfiles_path).join(&file.source).exists())
+        .map(|(root, target, file)| (root.clone(), target.clone(), file.hash.clone()))
+        .collect();
+
+    if orphans.is_empty() {
+        log_msg("No orphaned files found");
+        return Ok(());
+    }
+
+    for (root, target, hash) in orphans {
+        let path = Path::new(&root).join(&target);
+        let metadata = path.symlink_metadata();
+        let modified = metadata.as_ref().is_ok_and(|m| !m.is_symlink())
+            && hash_file(&path).ok().as_ref() != Some(&hash);
+
+        if modified && !force {
+            log_warning(&format!(
+                "`{}` was changed after it was deployed, keeping it (pass `--force` to remove it anyway)",
+                path.display()
+            ));
+            continue;
+        }
+
+        println!("{}", path.display());
+
+        if dry_run {
+            continue;
+        }
+
+        if metadata.is_ok() {
+            if let Err(e) = std::fs::remove_file(&path) {
+                log_error(&format!("failed to remove `{}`: {e}", path.display()));
+                continue;
+            }
+        }
+
+        state.forget(&root, &path);
+    }
+
+    if !dry_run {
+        state.write(&state_file)?;
+    }
+
+    Ok(())
+}
+
+/// Lists the entries of each directory containing targets that aren't targets themselves
+/// (or directories containing any), sorted. Directories are listed with a trailing `/`.
+fn unmanaged_files(targets: &[PathBuf]) -> BTreeMap<&Path, Vec<PathBuf>> {
+    let managed: HashSet<&Path> = targets.iter().flat_map(|t| t.ancestors()).collect();
+    let dirs: BTreeSet<&Path> = targets.iter().filter_map(|t| t.parent()).collect();
+
+    dirs.into_iter()
+        .map(|dir| {
+            let mut entries: Vec<_> = match std::fs::read_dir(dir) {
+                Ok(entries) => entries
+                    .filter_map(Result::ok)
+                    .map(|entry| entry.path())
+                    .filter(|path| !managed.contains(path.as_path()))
+                    .map(|path| if path.is_dir() { path.join("") } else { path })
+                    .collect(),
                 Err(e) => {
-                    log_error(&format!("{e}"));
-                    return Ok(());
+                    log_error(&format!("failed to read `{}`: {e}", dir.display()));
+                    Vec::new()
                 }
             };
+            entries.sort_unstable();
 
-            let target_path_str = path_str.replace(
-                &format!("{}/template", config.paths.dotfiles_path),
-                home_str,
-            );
-            let target_path = Path::new(&target_path_str);
-            let parent_dir = target_path.parent().unwrap();
+            (dir, entries)
+        })
+        .collect()
+}
+
+/// Resolves the directory to deploy into: either the one given explicitly, the one
+/// configured for this host, or the home directory
+fn target_root(dotfiles: &Dotfiles, target: Option<String>) -> String {
+    target
+        .or_else(|| {
+            let hostname = gethostname::gethostname();
+
+            dotfiles
+                .targets
+                .get(hostname.to_str()?)
+                .map(|t| config::expand_str(t))
+        })
+        .unwrap_or_else(|| home_dir().unwrap().to_string_lossy().to_string())
+}
+
+const DEFAULT_COMMIT_MESSAGE: &str = "Update dotfiles";
 
-            if !parent_dir.exists() {
-                std::fs::create_dir_all(&parent_dir)?;
+/// Fetches the dotfiles with the configured backend. With a commit message, local changes
+/// are committed and pushed as well. Otherwise, they're stashed around the pull if `stash`
+/// is set, or prevent it.
+fn sync(config: &Config, push: Option<&str>, stash: bool) -> Result<(), DotteryError> {
+    if push.is_some() && !matches!(config.sync.backend, SyncBackend::Git) {
+        log_error("pushing is only supported with the `git` backend");
+        return Ok(());
+    }
+
+    match config.sync.backend {
+        SyncBackend::Git => {
+            if let Some(message) = push {
+                commit_changes(message)?;
             }
 
-            std::fs::write(&target_path, output)?;
+            let changes = local_changes()?;
+            let stashed = !changes.is_empty();
 
-            process_sass(target_path);
+            if stashed {
+                log_warning("the dotfiles directory has uncommitted changes:");
+                changes.iter().for_each(|c| println!("{c}"));
 
-            Ok(())
-        })
-        .collect::<io::Result<()>>()
-}
+                if !stash {
+                    return Err(DotteryError::Git(
+                        "not pulling, commit the changes or pass `--stash`".into(),
+                    ));
+                }
 
-// TODO: Use an enum for package manager
-fn filter_packages<'a>(
-    packages: impl Iterator<Item = &'a Package>,
-    to_install: Option<&Vec<String>>,
-) -> (&'a str, Vec<&'a str>) {
-    // NOTE: Collecting into a `Vec<_>` isn't very efficient, but is preferred because
-    // makes the code more readable. Iterators are different types, so the `if let` would be a
-    // lot more cluttered.
-    if !is_yay_installed() {
-        (
-            "pacman",
-            packages
-                .into_iter()
-                .filter_map(|pkg| pkg.from_aur().then_some(pkg.name()))
-                .pipe(|pkgs| {
-                    if let Some(ps) = to_install {
-                        pkgs.filter(|pkg| ps.contains(&pkg.to_string())).collect()
-                    } else {
-                        pkgs.collect()
-                    }
-                }),
-        )
-    } else {
-        (
-            "yay",
-            packages.into_iter().map(Package::name).pipe(|pkgs| {
-                if let Some(ps) = to_install {
-                    pkgs.filter(|pkg| ps.contains(&pkg.to_string())).collect()
-                } else {
-                    pkgs.collect()
+                run_git_step(&["stash", "push", "--include-untracked"])?;
+            }
+
+            let remote = config.sync.remote();
+            let branch = config.sync.branch.as_deref();
+
+            let pulled = run_git_step(&[&["pull"], remote.as_slice(), branch.as_slice()].concat());
+
+            if stashed {
+                if let Err(e) = run_git_step(&["stash", "pop"]) {
+                    log_error(&e.to_string());
+                    log_warning("the local changes are kept in `git stash`");
                 }
-            }),
-        )
-    }
-}
+            }
 
-fn is_yay_installed() -> bool {
-    match process::Command::new("yay")
-        .arg("--version")
-        .stdout(Stdio::inherit())
-        .spawn()
-    {
-        Err(e) if e.kind() == io::ErrorKind::NotFound => false,
-        Err(e) => {
-            // Assume that it's just an error on user's side and
-            // let them know about it
-            log_error(&format!("{e}"));
+            // NOTE: Neither the submodules nor the remote should be updated after a failed pull
+            pulled?;
+
+            let submodules_synced = ["init", "sync", "update"].into_iter().all(|step| {
+                run_git_step(&["submodule", step])
+                    .map_err(|e| log_error(&e.to_string()))
+                    .is_ok()
+            });
+
+            if !submodules_synced {
+                log_warning("remaining submodule steps were skipped");
+            }
 
-            // Continue attempting to use `yay`
-            true
+            if push.is_some() {
+                // NOTE: The current branch may be named differently than the remote one
+                let refspec = branch.map(|b| format!("HEAD:{b}"));
+
+                run_git_step(
+                    &[&["push"], remote.as_slice(), refspec.as_deref().as_slice()].concat(),
+                )?;
+            }
         }
-        Ok(_) => true,
+        SyncBackend::Rsync => match config.sync.source {
+            Some(ref source) => {
+                let source = format!("{}/", source.trim_end_matches('/'));
+                let dest = format!("{}/", config.paths.dotfiles_path);
+
+                run_cmd!(rsync -a $source $dest).pipe(log_on_err);
+            }
+            None => log_error("`sync.source` must be set to sync with `rsync`"),
+        },
+        SyncBackend::None => log_msg("Syncing is disabled"),
     }
+
+    Ok(())
 }
 
-fn log_msg(msg: &str) {
-    println!("{} {}", ">>".bright_black(), msg.bold());
+/// Commits all local changes (staged or not), if there are any
+fn commit_changes(message: &str) -> Result<(), DotteryError> {
+    if local_changes()?.is_empty() {
+        log_verbose("No local changes to commit");
+        return Ok(());
+    }
+
+    run_git_step(&["add", "--all"])?;
+    run_git_step(&["commit", "-m", message])
 }
 
-fn log_error(msg: &str) {
-    eprintln!("{} {}", "ERROR:".bright_red(), msg.bold());
+/// Lists the files with uncommitted changes (in `git status --porcelain` format)
+fn local_changes() -> Result<Vec<String>, DotteryError> {
+    let output = process::Command::new("git")
+        .args(["status", "--porcelain"])
+        .stderr(process::Stdio::inherit())
+        .output()
+        .map_err(|e| DotteryError::Git(format!("failed to run `git status`: {e}")))?;
+
+    if !output.status.success() {
+        return Err(DotteryError::Git(format!(
+            "`git status` failed ({})",
+            output.status
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(String::from)
+        .collect())
 }
 
-fn log_on_err<T, E: Display>(result: Result<T, E>) {
-    let _ = result.map_err(|e| log_error(&format!("{e}")));
+/// Runs a git command (showing its own output), failing if it doesn't succeed
+fn run_git_step(args: &[&str]) -> Result<(), DotteryError> {
+    let step = format!("git {}", args.join(" "));
+
+    match process::Command::new("git").args(args).status() {
+        Ok(status) if status.success() => {
+            log_verbose(&format!("`{step}` succeeded"));
+
+            Ok(())
+        }
+        Ok(status) => Err(DotteryError::Git(format!("`{step}` failed ({status})"))),
+        Err(e) => Err(DotteryError::Git(format!("failed to run `{step}`: {e}"))),
+    }
 }
 
-fn process_sass<P: AsRef<Path>>(path: P) {
-    let sass_extensions: Vec<&OsStr> = ["sass", "scss"].into_iter().map(str::as_ref).collect();
-    let old_path = path.as_ref();
+/// Directory a git ref was extracted into by `export_ref`, until it's removed
+static SNAPSHOT: Mutex<Option<PathBuf>> = Mutex::new(None);
 
-    old_path.extension().map(|e| {
-        if sass_extensions.contains(&e) {
-            let new_path = old_path.with_extension("css");
+/// Extracts the tree of a git ref into a temporary directory, without touching the
+/// working tree. It has to be removed with `remove_snapshot`, even when exiting early.
+fn export_ref(git_ref: &str) -> io::Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("dottery-{}", process::id()));
 
-            let result = run_cmd! {
-                sass ${old_path} ${new_path} --no-source-map
-            };
+    std::fs::create_dir_all(&dir)?;
+    *SNAPSHOT.lock().unwrap() = Some(dir.clone());
 
-            log_on_err(result);
-        }
-        // } else {
-        //     Ok(())
-        // }
-    });
-    // .unwrap_or(Ok(()))
+    run_cmd!(git archive $git_ref | tar -x -C $dir)
+        .map(|_| dir.clone())
+        .tap_err(|_| remove_snapshot())
+}
+
+/// Removes the directory extracted by `export_ref`, if any
+fn remove_snapshot() {
+    if let Some(dir) = SNAPSHOT.lock().unwrap().take() {
+        std::fs::remove_dir_all(dir).pipe(log_on_err);
+    }
 }
@@ -0,0 +1,242 @@
+use std::{
+    collections::HashSet,
+    fmt::{self, Display},
+    io,
+    path::Path,
+    process::{self, ExitStatus, Stdio},
+};
+
+use cmd_lib::run_cmd;
+
+use crate::{
+    config::Package,
+    logging::{log_error, log_msg, log_on_err, log_warning},
+};
+
+/// Supported package managers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PackageManager {
+    Yay,
+    Pacman,
+    Apt,
+    Dnf,
+}
+
+impl PackageManager {
+    /// All supported package managers, in order of preference
+    pub const ALL: [Self; 4] = [Self::Yay, Self::Pacman, Self::Apt, Self::Dnf];
+
+    /// Finds the preferred package manager available on this system
+    pub fn detect() -> Option<Self> {
+        Self::ALL.into_iter().find(|m| command_exists(m.command()))
+    }
+
+    /// Name of the package manager's executable
+    pub fn command(&self) -> &'static str {
+        match self {
+            Self::Yay => "yay",
+            Self::Pacman => "pacman",
+            Self::Apt => "apt-get",
+            Self::Dnf => "dnf",
+        }
+    }
+
+    /// Arguments preceding the package names when installing packages
+    pub fn install_args(&self) -> Vec<&'static str> {
+        match self {
+            Self::Yay | Self::Pacman => vec!["-S", "--needed"],
+            Self::Apt | Self::Dnf => vec!["install", "-y"],
+        }
+    }
+
+    /// Argument that makes the package manager run non-interactively
+    pub fn noconfirm_arg(&self) -> &'static str {
+        match self {
+            Self::Yay | Self::Pacman => "--noconfirm",
+            Self::Apt | Self::Dnf => "-y",
+        }
+    }
+
+    /// Whether packages from the AUR can be installed
+    pub fn supports_aur(&self) -> bool {
+        matches!(self, Self::Yay)
+    }
+
+    /// Whether this is an Arch Linux package manager, where `from_aur` is meaningful
+    pub fn is_arch(&self) -> bool {
+        matches!(self, Self::Yay | Self::Pacman)
+    }
+}
+
+impl Display for PackageManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.command())
+    }
+}
+
+/// Checks whether an executable is available in `PATH`. Directories that can't be read
+/// are skipped.
+pub fn command_exists(cmd: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|paths| std::env::split_paths(&paths).any(|dir| is_executable(&dir.join(cmd))))
+        .unwrap_or(false)
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Selects the packages that can be installed with the package manager. `from_aur` is
+/// ignored outside of Arch.
+pub fn by_manager<'a>(
+    packages: impl Iterator<Item = &'a Package>,
+    manager: PackageManager,
+) -> impl Iterator<Item = &'a Package> {
+    packages.filter(move |pkg| manager.supports_aur() || !manager.is_arch() || !pkg.from_aur())
+}
+
+/// Selects the packages listed in `names`, or all of them if there's no list
+pub fn by_names<'a>(
+    packages: impl Iterator<Item = &'a Package>,
+    names: Option<&'a Vec<String>>,
+) -> impl Iterator<Item = &'a Package> {
+    packages.filter(move |pkg| names.is_none_or(|ns| ns.iter().any(|n| n == pkg.name())))
+}
+
+/// Maps the packages to their names for the package manager (their aliases for it, if
+/// any). Packages listed more than once are only yielded the first time.
+pub fn install_names<'a>(
+    packages: impl Iterator<Item = &'a Package>,
+    manager: PackageManager,
+) -> impl Iterator<Item = &'a str> {
+    let mut seen = HashSet::new();
+
+    packages
+        .map(move |pkg| pkg.install_name(manager))
+        .filter(move |name| seen.insert(*name))
+}
+
+/// Selects the names of packages that can be installed with the package manager,
+/// optionally only the ones listed in `to_install`
+pub fn filter_packages<'a>(
+    manager: PackageManager,
+    packages: impl Iterator<Item = &'a Package>,
+    to_install: Option<&'a Vec<String>>,
+) -> Vec<&'a str> {
+    install_names(by_names(by_manager(packages, manager), to_install), manager).collect()
+}
+
+/// Installs the packages, in batches of at most `batch_size` packages if given. Each batch
+/// is installed by a separate invocation, so that a failing package only fails its own
+/// batch. Returns the status of the last failed batch, if any failed, or the last error
+/// if the package manager couldn't be run for a batch (the remaining ones are still tried).
+pub fn install_pkgs<'a>(
+    manager: PackageManager,
+    base_args: &[&'a str],
+    noconfirm: bool,
+    batch_size: Option<usize>,
+    packages: impl Iterator<Item = &'a str>,
+) -> io::Result<ExitStatus> {
+    let mut args = base_args.to_vec();
+
+    // NOTE: The default (or configured) arguments may already include it
+    if noconfirm && !args.contains(&manager.noconfirm_arg()) {
+        args.push(manager.noconfirm_arg());
+    }
+
+    let packages: Vec<_> = packages.collect();
+
+    if packages.is_empty() {
+        // HACK: Should return signify that there's no packages to install
+        return Ok(ExitStatus::default());
+    }
+
+    let batches: Vec<_> = packages
+        .chunks(batch_size.unwrap_or(packages.len()).max(1))
+        .collect();
+    let mut result = Ok(ExitStatus::default());
+
+    for (i, batch) in batches.iter().enumerate() {
+        if batches.len() > 1 {
+            log_msg(&format!("Installing batch {}/{}", i + 1, batches.len()));
+        }
+
+        let status = match process::Command::new(manager.command())
+            .args(&args)
+            .args(*batch)
+            .stdin(Stdio::inherit())
+            .spawn()
+            .and_then(|mut c| c.wait())
+        {
+            Ok(status) => status,
+            Err(e) => {
+                if batches.len() > 1 {
+                    log_error(&format!(
+                        "batch {}/{} couldn't be installed, failed to run `{manager}`: {e}",
+                        i + 1,
+                        batches.len()
+                    ));
+                }
+
+                result = Err(e);
+                continue;
+            }
+        };
+
+        if !status.success() {
+            if batches.len() > 1 {
+                log_error(&format!(
+                    "batch {}/{} failed ({status}): {}",
+                    i + 1,
+                    batches.len(),
+                    batch.join(" ")
+                ));
+            }
+
+            if result.is_ok() {
+                result = Ok(status);
+            }
+        }
+    }
+
+    result
+}
+
+/// Runs post-installation hooks of the given packages
+pub fn run_post_install<'a>(packages: impl Iterator<Item = &'a Package>) {
+    packages
+        .filter_map(|pkg| pkg.post_install().map(|cmd| (pkg.name(), cmd)))
+        .for_each(|(name, cmd)| {
+            log_msg(&format!("Running post-install hook of `{name}`"));
+
+            log_on_err(run_cmd!(sh -c $cmd));
+        });
+}
+
+/// Runs verification commands of the given packages, returning the number of failures
+pub fn verify_pkgs<'a>(packages: impl Iterator<Item = &'a Package>) -> usize {
+    packages
+        .filter_map(|pkg| pkg.verify().map(|cmd| (pkg.name(), cmd)))
+        .filter(
+            |(name, cmd)| match process::Command::new("sh").args(["-c", cmd]).status() {
+                Ok(status) if status.success() => false,
+                Ok(status) => {
+                    log_warning(&format!("verification of `{name}` failed ({status})"));
+                    true
+                }
+                Err(e) => {
+                    log_warning(&format!("failed to verify `{name}`: {e}"));
+                    true
+                }
+            },
+        )
+        .count()
+}
@@ -0,0 +1,1482 @@
+use std::{
+    borrow::Cow,
+    ffi::OsStr,
+    fs::{canonicalize, File},
+    io::{self, BufWriter, ErrorKind::NotFound, Write},
+    iter,
+    num::NonZeroUsize,
+    path::{Component, Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use cmd_lib::run_cmd;
+use ignore::gitignore::Gitignore;
+use minijinja::{Environment, Template, UndefinedBehavior};
+use owo_colors::OwoColorize;
+use rayon::{prelude::*, ThreadPoolBuilder};
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+use tap::prelude::*;
+use walkdir::{DirEntry, WalkDir};
+
+use crate::{
+    config::{expand_str, Config, Dotfiles, EnvVars, MapRule, Templating},
+    logging::{
+        log_debug, log_error, log_msg, log_on_err, log_progress, log_verbose, log_warning,
+        FileStatus,
+    },
+    packages::command_exists,
+    state::{hash_bytes, hash_file, state_path, DeployState, Files},
+};
+
+/// Outcome of deploying dotfiles
+#[derive(Debug, Default)]
+pub struct DeployResults {
+    /// What happened to each file
+    pub actions: Vec<Action>,
+}
+
+impl DeployResults {
+    pub fn extend(&mut self, other: Self) {
+        self.actions.extend(other.actions);
+    }
+
+    /// Records the outcome of deploying a file, reporting its error (if any)
+    fn push(&mut self, action: Action) {
+        if let Some(ref e) = action.error {
+            log_error(e);
+        }
+
+        self.actions.push(action);
+    }
+
+    /// Returns the files with the given status
+    pub fn with_status(&self, status: Status) -> impl Iterator<Item = &Action> {
+        self.actions.iter().filter(move |a| a.status == status)
+    }
+
+    /// Targets that were left untouched because they already existed
+    pub fn skipped(&self) -> impl Iterator<Item = &Path> {
+        self.with_status(Status::Skipped)
+            .map(|a| a.target.as_path())
+    }
+
+    /// Describes how many files of each kind were deployed (including unchanged ones), e.g.
+    /// `57 raw files and 23 templates (20 unchanged, 3 skipped, 1 error)`
+    pub fn summary(&self) -> String {
+        let count = |kind| {
+            self.actions
+                .iter()
+                .filter(|a| {
+                    a.kind == kind && matches!(a.status, Status::Written | Status::Unchanged)
+                })
+                .count()
+        };
+        let plural = |n: usize, word: &str| match n {
+            1 => format!("{n} {word}"),
+            _ => format!("{n} {word}s"),
+        };
+
+        format!(
+            "{} and {} ({} unchanged, {} skipped, {})",
+            plural(count(Kind::Raw), "raw file"),
+            plural(count(Kind::Template), "template"),
+            self.with_status(Status::Unchanged).count(),
+            self.with_status(Status::Skipped).count(),
+            plural(self.with_status(Status::Error).count(), "error"),
+        )
+    }
+
+    /// Files that were written (or would be, on a dry run)
+    pub fn written(&self) -> impl Iterator<Item = &Path> {
+        self.with_status(Status::Written)
+            .map(|a| a.source.as_path())
+    }
+
+    /// Files that couldn't be deployed (e.g. templates that failed to render)
+    pub fn failed(&self) -> impl Iterator<Item = &Path> {
+        self.with_status(Status::Error).map(|a| a.source.as_path())
+    }
+}
+
+/// What happened to a deployed file
+#[derive(Debug, Serialize)]
+pub struct Action {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub kind: Kind,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Hash of the rendered template, if the target is compiled from it (see `Dotfiles::sass`)
+    #[serde(skip)]
+    pub input: Option<String>,
+}
+
+impl Action {
+    fn new(source: &Path, target: &Path, kind: Kind, outcome: Result<Outcome, String>) -> Self {
+        let (status, error) = match outcome {
+            Ok(Outcome::Written) => (Status::Written, None),
+            Ok(Outcome::Unchanged) => (Status::Unchanged, None),
+            Ok(Outcome::Skipped) => (Status::Skipped, None),
+            Err(e) => (Status::Error, Some(e)),
+        };
+
+        Self {
+            source: source.into(),
+            target: target.into(),
+            kind,
+            status,
+            error,
+            input: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Raw,
+    Template,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Written,
+    Unchanged,
+    Skipped,
+    Error,
+}
+
+pub fn copy_raw(
+    to_deploy: Option<&[String]>,
+    config: &Config,
+    dotfiles: &Dotfiles,
+    target_root: &str,
+    options: DeployOptions,
+) -> DeployResults {
+    let mut results = DeployResults::default();
+    let roots = allowed_roots(dotfiles, target_root);
+
+    walk_files(&config.paths.dotfiles_path, "raw", to_deploy).for_each(|f| {
+        let path_str = f
+            .path()
+            .to_string_lossy()
+            .to_string()
+            .tap(|p| log_progress(p));
+        let target_path = raw_target(&path_str, config, dotfiles, target_root);
+
+        let source = if options.link {
+            // NOTE: Relative links would resolve against the target's directory
+            canonicalize(f.path()).unwrap_or_else(|_| f.path().into())
+        } else {
+            f.path().into()
+        };
+        let contents = if options.link {
+            Contents::Linked(&source)
+        } else {
+            Contents::Copied(&source)
+        };
+
+        let outcome = if is_allowed_target(Path::new(&target_path), &roots) {
+            deploy_file(
+                f.path(),
+                Path::new(&target_path),
+                contents,
+                target_root,
+                options,
+            )
+            .map_err(|e| deploy_error(Path::new(&target_path), e))
+        } else {
+            Err(outside_target_error(&target_path))
+        };
+
+        results.push(Action::new(
+            f.path(),
+            Path::new(&target_path),
+            Kind::Raw,
+            outcome,
+        ));
+    });
+
+    results
+}
+
+/// Walks the files in a directory of the dotfiles (`raw` or `template`), optionally only
+/// the selected dotfiles (see `should_deploy`), skipping ones matched by `.dotteryignore`
+/// and reporting the ones that can't be read
+fn walk_files<'a>(
+    root: &str,
+    dir: &str,
+    to_deploy: Option<&'a [String]>,
+) -> impl Iterator<Item = DirEntry> + 'a {
+    let ignore = ignore_rules(root);
+
+    WalkDir::new(format!("{root}/{dir}/"))
+        .into_iter()
+        .filter_entry(move |e| {
+            !ignore
+                .matched_path_or_any_parents(e.path(), e.file_type().is_dir())
+                .is_ignore()
+                && should_deploy(e, to_deploy)
+        })
+        .filter_map(|r| match r {
+            Ok(d) => d.file_type().is_file().then_some(d),
+            // NOTE: Dotfiles don't have to have both `raw/` and `template/`
+            Err(e) if e.depth() == 0 && e.io_error().is_some_and(|e| e.kind() == NotFound) => None,
+            Err(e) => {
+                log_error(&format!("failed to read file: {e}"));
+                None
+            }
+        })
+}
+
+/// File (in the dotfiles directory) with gitignore-style patterns of files not to deploy
+const IGNORE_FILE: &str = ".dotteryignore";
+
+/// Reads the patterns of `.dotteryignore`, if it exists
+fn ignore_rules(root: &str) -> Gitignore {
+    let path = Path::new(root).join(IGNORE_FILE);
+
+    if !path.exists() {
+        return Gitignore::empty();
+    }
+
+    let (ignore, err) = Gitignore::new(&path);
+
+    if let Some(e) = err {
+        log_warning(&format!("failed to read `{IGNORE_FILE}`: {e}"));
+    }
+
+    ignore
+}
+
+/// Checks whether an entry is a directory (which has to be walked) or a file with one of
+/// the selected dotfiles in its path. All files are selected if none are given.
+fn should_deploy(entry: &DirEntry, to_deploy: Option<&[String]>) -> bool {
+    entry.file_type().is_dir()
+        || to_deploy.is_none_or(|ds| {
+            entry.path().components().any(|c| match c {
+                Component::Normal(d) => ds.iter().any(|s| *s == d.to_string_lossy()),
+                _ => false,
+            })
+        })
+}
+
+/// Walks the templates like `walk_files`, leaving out the partials they can include
+fn walk_templates<'a>(
+    config: &Config,
+    dotfiles: &Dotfiles,
+    to_deploy: Option<&'a [String]>,
+) -> impl Iterator<Item = DirEntry> + 'a {
+    let partials = partials_dir(&config.paths.dotfiles_path, &dotfiles.templating);
+
+    walk_files(&config.paths.dotfiles_path, "template", to_deploy)
+        .filter(move |f| !f.path().starts_with(&partials))
+}
+
+/// Directory of the partials templates can include or import
+fn partials_dir(root: &str, templating: &Templating) -> PathBuf {
+    Path::new(root).join("template").join(templating.partials())
+}
+
+/// Options affecting how files are deployed
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeployOptions {
+    /// Never overwrite existing files
+    pub only_new: bool,
+    /// Ask what to do with existing files that would be changed
+    pub interactive: bool,
+    /// Only log what would be written
+    pub dry_run: bool,
+    /// Symlink raw files instead of copying them
+    pub link: bool,
+    /// Move existing targets to a backup before overwriting them
+    pub backup: bool,
+    /// Print the changes to existing targets
+    pub diff: bool,
+    /// Make read-only targets writable if they can't be overwritten
+    pub force: bool,
+    /// Deploy SASS templates without compiling them
+    pub no_sass: bool,
+    /// Number of threads templates are processed by (the number of cores by default)
+    pub jobs: Option<NonZeroUsize>,
+}
+
+/// Contents of a deployed file
+enum Contents<'a> {
+    /// Output of a template, rendered when it's written
+    Rendered(&'a Template<'a, 'a>, &'a toml::Value),
+    /// Output of a template, already rendered
+    Buffered(&'a [u8]),
+    /// Raw file, copied as-is
+    Copied(&'a Path),
+    /// Raw file, symlinked into place
+    Linked(&'a Path),
+}
+
+impl Contents<'_> {
+    fn bytes(&self) -> io::Result<Cow<'_, [u8]>> {
+        match self {
+            Contents::Rendered(template, context) => template
+                .render(context)
+                .map(|s| Cow::Owned(s.into_bytes()))
+                .map_err(io::Error::other),
+            Contents::Buffered(bytes) => Ok(Cow::Borrowed(bytes)),
+            Contents::Copied(source) | Contents::Linked(source) => {
+                std::fs::read(source).map(Cow::Owned)
+            }
+        }
+    }
+}
+
+/// Whether a file was deployed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    Written,
+    /// The target is already identical
+    Unchanged,
+    Skipped,
+}
+
+/// Writes a file into its target location, creating missing parent directories
+fn deploy_file(
+    source: &Path,
+    target: &Path,
+    contents: Contents,
+    target_root: &str,
+    options: DeployOptions,
+) -> io::Result<Outcome> {
+    if target.is_dir() {
+        return Err(io::Error::other(format!(
+            "target exists as a directory: `{}`",
+            target.display()
+        )));
+    }
+
+    if let Some(file) = target.ancestors().skip(1).find(|p| p.is_file()) {
+        return Err(io::Error::other(format!(
+            "cannot deploy `{}`, `{}` exists as a file",
+            target.display(),
+            file.display()
+        )));
+    }
+
+    if let Contents::Linked(source) = contents {
+        if target.symlink_metadata().is_ok() {
+            if std::fs::read_link(target).is_ok_and(|l| l == source) {
+                return Ok(Outcome::Unchanged);
+            }
+
+            if !options.backup {
+                return Err(io::Error::other(format!(
+                    "`{}` already exists and isn't a link to `{}`, skipping",
+                    target.display(),
+                    source.display()
+                )));
+            }
+        }
+    }
+
+    if target.exists() && options.only_new {
+        return Ok(Outcome::Skipped);
+    }
+
+    // NOTE: Templates replacing an existing file are rendered once up front, so that the
+    // output that's compared (or diffed, or backed up) is also the one that's written
+    let rendered;
+    let contents = match contents {
+        Contents::Rendered(template, context) if target.symlink_metadata().is_ok() => {
+            rendered = template.render(context).map_err(io::Error::other)?;
+            Contents::Buffered(rendered.as_bytes())
+        }
+        contents => contents,
+    };
+
+    if options.interactive && target.exists() {
+        let old = std::fs::read(target)?;
+        let new = contents.bytes()?;
+
+        if *old != *new && !resolve_conflict(target, &old, &new)? {
+            return Ok(Outcome::Skipped);
+        }
+    }
+
+    if options.diff && !matches!(contents, Contents::Linked(_)) {
+        if let Ok(old) = std::fs::read(target) {
+            let new = contents.bytes()?;
+
+            if *old != *new {
+                print_diff(target, &old, &new);
+            }
+        }
+    }
+
+    // NOTE: Leaving identical files alone keeps their modification times
+    let up_to_date = match contents {
+        Contents::Rendered(..) | Contents::Buffered(_) => is_up_to_date(source, target, &contents)?,
+        Contents::Copied(source) => is_same_copy(source, target)?,
+        Contents::Linked(_) => false,
+    };
+
+    if up_to_date {
+        log_verbose(&format!("`{}` is up to date", target.display()));
+
+        return Ok(Outcome::Unchanged);
+    }
+
+    if options.dry_run {
+        // NOTE: Templates are still rendered to report their errors
+        if let Contents::Rendered(template, context) = contents {
+            template
+                .render_to_write(context, io::sink())
+                .map_err(io::Error::other)?;
+        }
+
+        log_msg(&format!("`{}` -> `{}`", source.display(), target.display()));
+
+        return Ok(Outcome::Written);
+    }
+
+    // NOTE: There's nothing to preserve if the contents wouldn't change
+    if options.backup
+        && target.symlink_metadata().is_ok()
+        && (matches!(contents, Contents::Linked(_))
+            || std::fs::read(target).ok().as_deref() != Some(&contents.bytes()?[..]))
+    {
+        let backup_path = backup(target)?;
+        log_msg(&format!("Backed up to `{}`", backup_path.display()));
+    }
+
+    log_debug(&format!("`{}` -> `{}`", source.display(), target.display()));
+
+    let parent_dir = target.parent().unwrap();
+
+    if !parent_dir.exists() {
+        std::fs::create_dir_all(parent_dir)?;
+    }
+
+    check_resolved_target(target, target_root);
+
+    let write = || match contents {
+        Contents::Rendered(template, context) => write_replacing(source, target, |writer| {
+            template
+                .render_to_write(context, writer)
+                .map(|_| ())
+                .map_err(io::Error::other)
+        }),
+        Contents::Buffered(bytes) => {
+            write_replacing(source, target, |writer| writer.write_all(bytes))
+        }
+        Contents::Copied(source) => std::fs::copy(source, target).map(|_| ()),
+        Contents::Linked(source) => std::os::unix::fs::symlink(source, target),
+    };
+
+    match write() {
+        Err(e) if options.force && e.kind() == io::ErrorKind::PermissionDenied => {
+            make_writable(target)?;
+            write()
+        }
+        result => result,
+    }
+    .map(|_| Outcome::Written)
+}
+
+/// Logs where a target actually ends up (with `-v`), warning if a symlinked directory moves
+/// it out of the target directory
+fn check_resolved_target(target: &Path, target_root: &str) {
+    let (Some(parent), Some(name)) = (target.parent(), target.file_name()) else {
+        return;
+    };
+    let Ok(resolved) = canonicalize(parent).map(|p| p.join(name)) else {
+        return;
+    };
+
+    log_verbose(&format!(
+        "`{}` resolves to `{}`",
+        target.display(),
+        resolved.display()
+    ));
+
+    // NOTE: Targets mapped outside of the target directory (see `allowed_targets`) are
+    // placed there on purpose
+    let moved_out = target.starts_with(target_root)
+        && canonicalize(target_root).is_ok_and(|root| !resolved.starts_with(root));
+
+    if moved_out {
+        log_warning(&format!(
+            "`{}` is deployed to `{}`, since one of its parent directories is a symlink",
+            target.display(),
+            resolved.display()
+        ));
+    }
+}
+
+/// Gives the owner write permission on a read-only target, or removes it if that's not
+/// possible, so that it can be overwritten
+fn make_writable(target: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    log_debug(&format!("Making `{}` writable", target.display()));
+
+    let mut permissions = std::fs::metadata(target)?.permissions();
+    permissions.set_mode(permissions.mode() | 0o200);
+
+    std::fs::set_permissions(target, permissions).or_else(|_| std::fs::remove_file(target))
+}
+
+/// Checks whether a target exists with the given contents and the permissions of its source
+fn is_up_to_date(source: &Path, target: &Path, contents: &Contents) -> io::Result<bool> {
+    let same_permissions = std::fs::metadata(target)
+        .and_then(|t| Ok(t.permissions() == std::fs::metadata(source)?.permissions()));
+
+    match (same_permissions, std::fs::read(target)) {
+        (Ok(true), Ok(old)) => Ok(old[..] == contents.bytes()?[..]),
+        _ => Ok(false),
+    }
+}
+
+/// Checks whether a target is a regular file identical to its source, with the same
+/// permissions. Sizes are compared first, so that files are only hashed if they might match.
+fn is_same_copy(source: &Path, target: &Path) -> io::Result<bool> {
+    let Ok(old) = target.symlink_metadata() else {
+        return Ok(false);
+    };
+    let new = std::fs::metadata(source)?;
+
+    if !old.is_file() || old.len() != new.len() || old.permissions() != new.permissions() {
+        return Ok(false);
+    }
+
+    Ok(hash_file(source)? == hash_file(target)?)
+}
+
+/// Writes the output of a template into a temporary file, which then replaces the target,
+/// so that it's left untouched if rendering fails. The output gets the permissions of the
+/// template (e.g. to keep scripts executable).
+fn write_replacing(
+    source: &Path,
+    target: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> io::Result<()> {
+    // NOTE: Renaming would replace a symlinked target with a regular file, while raw files
+    // are copied through the link (which may also be dangling)
+    let resolved;
+    let target = match target.symlink_metadata() {
+        Ok(metadata) if metadata.is_symlink() => {
+            resolved = canonicalize(target).or_else(|_| {
+                std::fs::read_link(target).map(|link| target.parent().unwrap().join(link))
+            })?;
+            &resolved
+        }
+        _ => target,
+    };
+
+    // NOTE: Renaming would replace a read-only target, which copying raw files refuses to
+    if target.exists() {
+        File::options().write(true).open(target)?;
+    }
+
+    let temp_path = PathBuf::from(format!("{}.dottery-tmp", target.display()));
+
+    let result = File::create(&temp_path).and_then(|f| {
+        let mut writer = BufWriter::new(f);
+
+        write(&mut writer)?;
+        writer.flush()?;
+
+        std::fs::set_permissions(&temp_path, std::fs::metadata(source)?.permissions())
+    });
+
+    match result {
+        Ok(()) => std::fs::rename(&temp_path, target),
+        Err(e) => {
+            log_on_err(std::fs::remove_file(&temp_path));
+            Err(e)
+        }
+    }
+}
+
+/// Asks the user what to do with a target that would be changed. Returns whether it
+/// should be overwritten.
+fn resolve_conflict(target: &Path, old: &[u8], new: &[u8]) -> io::Result<bool> {
+    loop {
+        let choice = prompt(
+            &format!("`{}` has been changed", target.display()),
+            &[
+                ('o', "overwrite"),
+                ('s', "skip"),
+                ('d', "show diff"),
+                ('b', "backup and overwrite"),
+                ('a', "abort"),
+            ],
+        )?;
+
+        match choice {
+            'o' => return Ok(true),
+            's' => return Ok(false),
+            'd' => print_diff(target, old, new),
+            'b' => {
+                let backup_path = backup(target)?;
+                log_msg(&format!("Backed up to `{}`", backup_path.display()));
+
+                return Ok(true);
+            }
+            _ => {
+                log_msg("Aborting");
+                crate::remove_snapshot();
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// Asks the user to pick one of the choices by its key, until a valid one is given
+pub fn prompt(question: &str, choices: &[(char, &str)]) -> io::Result<char> {
+    let options = choices
+        .iter()
+        .map(|(key, desc)| format!("[{key}] {desc}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    loop {
+        log_msg(&format!("{question}: {options}"));
+
+        let mut answer = String::new();
+
+        if io::stdin().read_line(&mut answer)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "no answer given",
+            ));
+        }
+
+        let answer = answer.trim().to_lowercase();
+
+        if let Some((key, _)) = choices.iter().find(|(key, _)| answer.chars().eq([*key])) {
+            return Ok(*key);
+        }
+    }
+}
+
+/// Prints a unified diff between the current and the new contents of a file
+fn print_diff(target: &Path, old: &[u8], new: &[u8]) {
+    let (Ok(old), Ok(new)) = (std::str::from_utf8(old), std::str::from_utf8(new)) else {
+        println!("Binary files differ");
+        return;
+    };
+
+    let path = target.display().to_string();
+
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .header(&path, &path)
+        .to_string()
+        .lines()
+        .for_each(|line| match line.chars().next() {
+            Some('+') if !line.starts_with("+++") => println!("{}", line.green()),
+            Some('-') if !line.starts_with("---") => println!("{}", line.red()),
+            _ => println!("{line}"),
+        });
+}
+
+/// Moves a file to `<path>.bak` (or `<path>.<timestamp>.bak`, if that's taken)
+fn backup(path: &Path) -> io::Result<PathBuf> {
+    let mut backup_path = PathBuf::from(format!("{}.bak", path.display()));
+
+    if backup_path.exists() {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        backup_path = PathBuf::from(format!("{}.{timestamp}.bak", path.display()));
+    }
+
+    std::fs::rename(path, &backup_path).map(|_| backup_path)
+}
+
+pub fn process_templates(
+    to_deploy: Option<Vec<String>>,
+    settings: toml::Value,
+    config: &Config,
+    dotfiles: &Dotfiles,
+    target_root: &str,
+    options: DeployOptions,
+) -> io::Result<DeployResults> {
+    let binary_extensions = binary_extensions(config);
+
+    let env = environment(&config.paths.dotfiles_path, &dotfiles.templating);
+    let context = template_context(settings, dotfiles);
+    let roots = allowed_roots(dotfiles, target_root);
+    // NOTE: Compiled templates that aren't kept can only be compared by what's recorded
+    let recorded: Files = DeployState::read_or_default(state_path(config))
+        .map(|mut state| state.roots.remove(target_root).unwrap_or_default())
+        .unwrap_or_default();
+
+    let files: Vec<DirEntry> = walk_templates(config, dotfiles, to_deploy.as_deref()).collect();
+
+    // NOTE: Problems with a template are reported in its action, so that the others are
+    // still deployed
+    let process = |f: &DirEntry| -> DeployResults {
+        let mut results = DeployResults::default();
+
+        let path = f.path();
+        let path_str = path.to_str().unwrap();
+
+        log_progress(path_str);
+
+        let target_path_str = template_target(path_str, config, dotfiles, target_root);
+        let target_path = Path::new(&target_path_str);
+
+        let action = |outcome| Action::new(path, target_path, Kind::Template, outcome);
+
+        if !is_allowed_target(target_path, &roots) {
+            results.push(action(Err(outside_target_error(&target_path_str))));
+            return results;
+        }
+
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                results.push(action(Err(format!("failed to read `{path_str}`: {e}"))));
+                return results;
+            }
+        };
+
+        let text = (!has_binary_extension(path, &binary_extensions) && !is_binary(&bytes))
+            .then(|| String::from_utf8(bytes).ok())
+            .flatten();
+
+        let source = match text
+            .as_deref()
+            .map(|t| apply_front_matter(t, &env, &context, &dotfiles.templating))
+        {
+            Some(Ok(Some(source))) => Some(source),
+            Some(Ok(None)) => {
+                log_verbose(&format!("Skipping `{path_str}`, its condition is false"));
+                return results;
+            }
+            Some(Err(e)) => {
+                results.push(action(Err(format!("failed to render `{path_str}`: {e}"))));
+                return results;
+            }
+            None => None,
+        };
+
+        let name = template_name(&config.paths.dotfiles_path, path);
+        let template = match source {
+            Some(ref contents) => match env.template_from_named_str(&name, contents) {
+                Ok(t) => Some(t),
+                Err(e) => {
+                    results.push(action(Err(format!("failed to render `{path_str}`: {e}"))));
+                    return results;
+                }
+            },
+            None => None,
+        };
+
+        let compiled = template
+            .as_ref()
+            .and_then(|t| compiled_output(target_path, dotfiles, options.no_sass).map(|o| (t, o)));
+        let rendered = match compiled {
+            Some((t, ref output)) => match t.render(&context) {
+                Ok(rendered) => Some((rendered, output)),
+                Err(e) => {
+                    results.push(action(Err(format!(
+                        "failed to render `{path_str}`: {}",
+                        describe_render_error(&e)
+                    ))));
+                    return results;
+                }
+            },
+            None => None,
+        };
+        let input = rendered.as_ref().map(|(r, _)| hash_bytes(r));
+
+        if let (Some((_, output)), Some(input)) = (&rendered, &input) {
+            let up_to_date = output
+                .strip_prefix(target_root)
+                .ok()
+                .and_then(|o| recorded.get(o.to_string_lossy().as_ref()))
+                .is_some_and(|f| {
+                    f.input.as_ref() == Some(input) && hash_file(output).is_ok_and(|h| h == f.hash)
+                });
+
+            if up_to_date && !target_path.exists() {
+                log_verbose(&format!("`{}` is up to date", output.display()));
+
+                let mut action = Action::new(path, output, Kind::Template, Ok(Outcome::Unchanged));
+                action.input = Some(input.clone());
+                results.push(action);
+
+                return results;
+            }
+        }
+
+        // NOTE: Binary files can't be templates, so they're copied verbatim
+        let contents = match (&rendered, &template) {
+            (Some((r, _)), _) => Contents::Buffered(r.as_bytes()),
+            (None, Some(t)) => Contents::Rendered(t, &context),
+            (None, None) => Contents::Copied(path),
+        };
+
+        let outcome = deploy_file(path, target_path, contents, target_root, options).map_err(|e| {
+            match render_error(&e) {
+                Some(re) => format!(
+                    "failed to render `{path_str}`: {}",
+                    describe_render_error(re)
+                ),
+                None => deploy_error(target_path, e),
+            }
+        });
+        let written = match outcome {
+            Ok(Outcome::Written) => Some(true),
+            Ok(Outcome::Unchanged) => Some(false),
+            _ => None,
+        };
+
+        let mut deployed = action(outcome);
+
+        // NOTE: The deployed template is removed once it's compiled, so its output is
+        // recorded instead
+        if let (Some((_, output)), Some(_)) = (&rendered, written) {
+            deployed.target = output.to_path_buf();
+            deployed.input = input;
+        }
+
+        results.push(deployed);
+
+        if let (Some(written), false) = (written, options.dry_run) {
+            postprocess(target_path, written, dotfiles, options.no_sass);
+        }
+
+        results
+    };
+
+    // NOTE: Prompts have to be answered (and diffs read) one at a time
+    if options.interactive || options.diff {
+        Ok(files
+            .iter()
+            .map(process)
+            .fold(DeployResults::default(), |mut rs, r| {
+                rs.extend(r);
+                rs
+            }))
+    } else {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(options.jobs.map_or(0, NonZeroUsize::get))
+            .build()
+            .map_err(io::Error::other)?;
+
+        Ok(pool.install(|| {
+            files
+                .par_iter()
+                .map(process)
+                .reduce(DeployResults::default, |mut rs, r| {
+                    rs.extend(r);
+                    rs
+                })
+        }))
+    }
+}
+
+/// Creates the environment templates are rendered in, using the configured delimiters.
+/// Templates can inline other files (relative to `root`) with `include_file`, and include
+/// or import the partials by their path in the partials directory.
+fn environment(root: &str, templating: &Templating) -> Environment<'static> {
+    let mut env = Environment::new();
+
+    env.set_loader(minijinja::path_loader(partials_dir(root, templating)));
+
+    let root = PathBuf::from(root);
+
+    env.add_function("include_file", move |path: String| {
+        std::fs::read_to_string(root.join(&path)).map_err(|e| {
+            minijinja::Error::new(
+                minijinja::ErrorKind::InvalidOperation,
+                format!("failed to include `{path}`: {e}"),
+            )
+        })
+    });
+
+    // NOTE: The delimiters are checked when the manifest is read
+    env.set_syntax(templating.syntax())
+        .expect("template delimiters should be valid");
+
+    if templating.strict {
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+    }
+
+    env
+}
+
+/// Names a template by its path in the dotfiles directory, so that errors point at the file
+fn template_name(root: &str, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Returns the error of rendering a template, if it caused this one
+fn render_error(e: &io::Error) -> Option<&minijinja::Error> {
+    e.get_ref()
+        .and_then(|inner| inner.downcast_ref::<minijinja::Error>())
+}
+
+/// Describes a rendering error, along with the expression that's undefined (or the line
+/// containing it, if it can't be pinpointed) if that's the cause
+fn describe_render_error(e: &minijinja::Error) -> String {
+    let source = match e.template_source() {
+        Some(s) if e.kind() == minijinja::ErrorKind::UndefinedError => s,
+        _ => return e.to_string(),
+    };
+
+    let expr = e.range().and_then(|r| source.get(r));
+    let line = e
+        .line()
+        .and_then(|n| source.lines().nth(n.saturating_sub(1)))
+        .map(str::trim);
+
+    match (expr, line) {
+        (Some(expr), _) => format!("{e}: `{expr}` is undefined"),
+        (None, Some(line)) => format!("{e}: `{line}` uses an undefined value"),
+        (None, None) => e.to_string(),
+    }
+}
+
+/// Extensions of templates that are copied as-is, unless configured otherwise
+const BIN_EXTENSIONS: [&str; 2] = ["png", "jpg"];
+
+/// Returns the configured extensions of binary templates or the built-in ones
+fn binary_extensions(config: &Config) -> Vec<String> {
+    config
+        .files
+        .binary_extensions
+        .clone()
+        .unwrap_or_else(|| BIN_EXTENSIONS.map(String::from).to_vec())
+}
+
+/// Checks whether the extension of a file is one of the given ones (ignoring case)
+fn has_binary_extension(path: &Path, extensions: &[String]) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|e| extensions.iter().any(|b| b.eq_ignore_ascii_case(e)))
+}
+
+/// Number of leading bytes inspected to tell binary files from text
+const SNIFF_LEN: usize = 8192;
+
+/// Checks whether the beginning of a file contains a NUL byte or isn't valid UTF-8
+fn is_binary(bytes: &[u8]) -> bool {
+    let head = &bytes[..bytes.len().min(SNIFF_LEN)];
+
+    head.contains(&0)
+        || match std::str::from_utf8(head) {
+            Ok(_) => false,
+            // A character may be cut off at the end of the inspected part
+            Err(e) => e.error_len().is_some(),
+        }
+}
+
+/// Names in the template context that are reserved for injected values
+const RESERVED_VARS: [&str; 3] = ["vars", "host", "env"];
+
+/// Assembles the values available in templates: user variables under `vars`, facts
+/// about this machine under `host` and environment variables under `env`
+fn template_context(vars: toml::Value, dotfiles: &Dotfiles) -> toml::Value {
+    let mut context = toml::Table::new();
+
+    if dotfiles.flat_vars {
+        if let toml::Value::Table(ref vs) = vars {
+            vs.iter().for_each(|(k, v)| {
+                if RESERVED_VARS.contains(&k.as_str()) {
+                    log_warning(&format!(
+                        "variable `{k}` is shadowed by a built-in one, use `vars.{k}` instead"
+                    ));
+                } else {
+                    context.insert(k.clone(), v.clone());
+                }
+            });
+        }
+    }
+
+    context.insert("vars".into(), vars);
+    context.insert("host".into(), host_facts());
+    context.insert("env".into(), env_vars(&dotfiles.env));
+
+    toml::Value::Table(context)
+}
+
+/// Environment variables that are always available in templates
+const DEFAULT_ENV_VARS: [&str; 9] = [
+    "HOME",
+    "USER",
+    "SHELL",
+    "LANG",
+    "XDG_CONFIG_HOME",
+    "XDG_DATA_HOME",
+    "XDG_STATE_HOME",
+    "XDG_CACHE_HOME",
+    "XDG_RUNTIME_DIR",
+];
+
+/// Collects the allowed (or all) environment variables that are set
+pub fn env_vars(config: &EnvVars) -> toml::Value {
+    let vars: toml::Table = if config.all {
+        std::env::vars().map(|(k, v)| (k, v.into())).collect()
+    } else {
+        DEFAULT_ENV_VARS
+            .into_iter()
+            .chain(config.allow.iter().map(String::as_str))
+            .filter_map(|k| std::env::var(k).ok().map(|v| (k.to_string(), v.into())))
+            .collect()
+    };
+
+    toml::Value::Table(vars)
+}
+
+/// Facts about this machine, available to templates as `host`
+pub fn host_facts() -> toml::Value {
+    let mut facts = toml::Table::new();
+
+    facts.insert(
+        "hostname".into(),
+        gethostname::gethostname()
+            .to_string_lossy()
+            .to_string()
+            .into(),
+    );
+    facts.insert("os".into(), std::env::consts::OS.into());
+    facts.insert("family".into(), std::env::consts::FAMILY.into());
+    facts.insert("arch".into(), std::env::consts::ARCH.into());
+
+    toml::Value::Table(facts)
+}
+
+/// Strips the dotfiles directory from the path of a file
+fn relative_path<'a>(path_str: &'a str, config: &Config) -> &'a str {
+    path_str
+        .strip_prefix(&config.paths.dotfiles_path)
+        .unwrap_or(path_str)
+        .trim_start_matches('/')
+}
+
+/// Compares the targets of all raw files and templates with what would be deployed
+pub fn deploy_status(
+    settings: toml::Value,
+    config: &Config,
+    dotfiles: &Dotfiles,
+    target_root: &str,
+) -> Vec<(String, FileStatus)> {
+    let env = environment(&config.paths.dotfiles_path, &dotfiles.templating);
+    let context = template_context(settings, dotfiles);
+    let binary_extensions = binary_extensions(config);
+
+    let raw = walk_files(&config.paths.dotfiles_path, "raw", None).map(|f| {
+        let target = raw_target(&f.path().to_string_lossy(), config, dotfiles, target_root);
+
+        let expected = std::fs::read(f.path()).map(Some);
+
+        (f, target, expected)
+    });
+    let templates = walk_templates(config, dotfiles, None).map(|f| {
+        let path_str = f.path().to_string_lossy();
+        let target = template_target(&path_str, config, dotfiles, target_root);
+        let name = template_name(&config.paths.dotfiles_path, f.path());
+        let expected = expected_output(
+            &env,
+            &context,
+            f.path(),
+            &name,
+            dotfiles,
+            &binary_extensions,
+        );
+
+        (f, target, expected)
+    });
+
+    raw.chain(templates)
+        .filter_map(|(f, target, expected)| match expected {
+            Ok(Some(bytes)) => Some((target.clone(), file_status(Path::new(&target), &bytes))),
+            Ok(None) => None,
+            Err(e) => {
+                log_error(&format!("failed to process `{}`: {e}", f.path().display()));
+                None
+            }
+        })
+        .collect()
+}
+
+/// Renders a template in memory, or reads it as-is if it's binary. Returns `None` if the
+/// template's front matter excludes it.
+fn expected_output(
+    env: &Environment,
+    context: &toml::Value,
+    path: &Path,
+    name: &str,
+    dotfiles: &Dotfiles,
+    binary_extensions: &[String],
+) -> io::Result<Option<Vec<u8>>> {
+    let bytes = std::fs::read(path)?;
+
+    if has_binary_extension(path, binary_extensions) || is_binary(&bytes) {
+        return Ok(Some(bytes));
+    }
+
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => return Ok(Some(e.into_bytes())),
+    };
+
+    match apply_front_matter(&text, env, context, &dotfiles.templating).map_err(io::Error::other)? {
+        Some(source) => env
+            .render_named_str(name, &source, context)
+            .map(|s| Some(s.into_bytes()))
+            .map_err(io::Error::other),
+        None => Ok(None),
+    }
+}
+
+/// Options a template can set in a comment at its very beginning, e.g.
+/// `{# dottery: when = "host.os == 'linux'" #}`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FrontMatter {
+    /// Expression that has to be true for the template to be deployed
+    when: Option<String>,
+}
+
+/// Reads the front matter of a template, if it has any. Returns the source to render, or
+/// `None` if the front matter's condition is false.
+fn apply_front_matter<'a>(
+    text: &'a str,
+    env: &Environment,
+    context: &toml::Value,
+    templating: &Templating,
+) -> Result<Option<Cow<'a, str>>, String> {
+    let syntax = templating.syntax();
+    let (start, end) = (syntax.comment_start.as_ref(), syntax.comment_end.as_ref());
+
+    let Some(rest) = text
+        .strip_prefix(start)
+        .and_then(|r| r.trim_start().strip_prefix("dottery:"))
+    else {
+        return Ok(Some(Cow::Borrowed(text)));
+    };
+    let Some((header, _)) = rest.split_once(end) else {
+        return Err("front matter isn't closed".into());
+    };
+
+    let front_matter: FrontMatter = toml::from_str(header.trim_end_matches('-'))
+        .map_err(|e| format!("invalid front matter: {}", e.message()))?;
+
+    if let Some(ref expr) = front_matter.when {
+        let value = env
+            .compile_expression(expr)
+            .and_then(|e| e.eval(context))
+            .map_err(|e| format!("failed to evaluate `{expr}`: {e}"))?;
+
+        if !value.is_true() {
+            return Ok(None);
+        }
+    }
+
+    // NOTE: The front matter is kept (as a comment that trims the whitespace after it), so
+    // that line numbers in errors stay the same
+    let header_end = text.len() - rest.len() + header.len();
+
+    Ok(Some(Cow::Owned(format!(
+        "{}-{}",
+        &text[..header_end],
+        &text[header_end..]
+    ))))
+}
+
+/// Compares a file on disk with its expected contents
+fn file_status(target: &Path, expected: &[u8]) -> FileStatus {
+    match std::fs::read(target) {
+        Ok(actual) if actual == expected => FileStatus::Identical,
+        Ok(_) => FileStatus::Modified,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => FileStatus::Missing,
+        Err(_) => FileStatus::Modified,
+    }
+}
+
+/// Signature of `raw_target` and `template_target`
+type TargetFn = fn(&str, &Config, &Dotfiles, &str) -> String;
+
+/// A raw file or template, along with where it's deployed
+#[derive(Debug, Serialize)]
+pub struct ManagedFile {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub kind: Kind,
+}
+
+/// Lists all raw files and templates that would be deployed, with their targets
+pub fn managed_files(config: &Config, dotfiles: &Dotfiles, target_root: &str) -> Vec<ManagedFile> {
+    let raw = walk_files(&config.paths.dotfiles_path, "raw", None)
+        .map(|f| (f, Kind::Raw, raw_target as TargetFn));
+    let templates = walk_templates(config, dotfiles, None)
+        .map(|f| (f, Kind::Template, template_target as TargetFn));
+
+    raw.chain(templates)
+        .map(|(f, kind, target)| ManagedFile {
+            target: target(&f.path().to_string_lossy(), config, dotfiles, target_root).into(),
+            source: f.into_path(),
+            kind,
+        })
+        .collect()
+}
+
+/// Finds the existing targets of all raw files and templates
+pub fn deployed_targets(config: &Config, dotfiles: &Dotfiles, target_root: &str) -> Vec<PathBuf> {
+    managed_files(config, dotfiles, target_root)
+        .into_iter()
+        .map(|f| f.target)
+        .filter(|t| t.symlink_metadata().is_ok_and(|m| !m.is_dir()))
+        .collect()
+}
+
+/// Returns the directories files may be deployed into: the target directory, the
+/// `allowed_targets` and the XDG base directories outside of the home directory (which
+/// `@config` etc. may resolve to)
+fn allowed_roots(dotfiles: &Dotfiles, target_root: &str) -> Vec<PathBuf> {
+    let home = dirs::home_dir();
+    let xdg_dirs = [
+        dirs::config_dir(),
+        dirs::data_dir(),
+        dirs::state_dir(),
+        dirs::cache_dir(),
+    ]
+    .into_iter()
+    .flatten()
+    .filter(|d| home.as_ref().is_none_or(|h| !d.starts_with(h)));
+
+    iter::once(target_root.into())
+        .chain(
+            dotfiles
+                .allowed_targets
+                .iter()
+                .map(|p| expand_str(p).into()),
+        )
+        .chain(xdg_dirs)
+        .filter_map(|p: PathBuf| normalize(&p))
+        .collect()
+}
+
+/// Checks whether a target is inside one of the allowed directories. The path is compared
+/// as-is (symlinks in it aren't resolved), and can't contain `..`.
+fn is_allowed_target(target: &Path, roots: &[PathBuf]) -> bool {
+    normalize(target).is_some_and(|t| roots.iter().any(|r| t.starts_with(r)))
+}
+
+/// Drops the `.` components of a path, unless it contains `..`
+fn normalize(path: &Path) -> Option<PathBuf> {
+    path.components()
+        .filter(|c| *c != Component::CurDir)
+        .map(|c| (c != Component::ParentDir).then_some(c))
+        .collect()
+}
+
+fn outside_target_error(target: &str) -> String {
+    format!("`{target}` is outside of the target directory, skipping it (see `allowed_targets`)")
+}
+
+/// Describes an error from deploying to the target, naming the target unless the error
+/// already does (only OS errors don't)
+fn deploy_error(target: &Path, e: io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::Other => e.to_string(),
+        _ => format!("failed to deploy `{}`: {e}", target.display()),
+    }
+}
+
+/// Computes where a raw file is deployed
+fn raw_target(path_str: &str, config: &Config, dotfiles: &Dotfiles, target_root: &str) -> String {
+    map_target(&dotfiles.map, path_str, config, target_root).unwrap_or_else(|| {
+        let target = path_str.replace(&format!("{}/raw", config.paths.dotfiles_path), target_root);
+
+        resolve_xdg_prefix(target, target_root)
+    })
+}
+
+/// Computes where a template is deployed
+fn template_target(
+    path_str: &str,
+    config: &Config,
+    dotfiles: &Dotfiles,
+    target_root: &str,
+) -> String {
+    map_target(&dotfiles.map, path_str, config, target_root).unwrap_or_else(|| {
+        let target = path_str.replace(
+            &format!("{}/template", config.paths.dotfiles_path),
+            target_root,
+        );
+        let target = resolve_xdg_prefix(target, target_root);
+
+        match dotfiles.template_suffix {
+            Some(ref suffix) => suffix.strip(relative_path(path_str, config), target),
+            None => target,
+        }
+    })
+}
+
+/// Replaces a leading `@config`, `@data`, `@state` or `@cache` directory (e.g. of
+/// `@config/nvim/init.lua`) with the corresponding XDG base directory. When deploying
+/// somewhere else than the home directory, base directories inside the home directory are
+/// placed in the target directory instead.
+fn resolve_xdg_prefix(target: String, target_root: &str) -> String {
+    let Some(rest) = target
+        .strip_prefix(target_root)
+        .and_then(|t| t.strip_prefix("/@"))
+    else {
+        return target;
+    };
+    let (name, rest) = rest.split_once('/').unwrap_or((rest, ""));
+
+    let dir = match name {
+        "config" => dirs::config_dir(),
+        "data" => dirs::data_dir(),
+        "state" => dirs::state_dir(),
+        "cache" => dirs::cache_dir(),
+        _ => return target,
+    };
+    let Some(dir) = dir else {
+        log_warning(&format!(
+            "no `{name}` directory on this system, deploying `{target}` as-is"
+        ));
+        return target;
+    };
+
+    let dir = match dirs::home_dir().and_then(|h| dir.strip_prefix(h).ok().map(Path::to_path_buf)) {
+        Some(relative) => Path::new(target_root).join(relative),
+        None => dir,
+    };
+
+    dir.join(rest).to_string_lossy().into()
+}
+
+/// Computes the target path of a file using the first matching mapping rule, if any
+fn map_target(
+    rules: &[MapRule],
+    path_str: &str,
+    config: &Config,
+    target_root: &str,
+) -> Option<String> {
+    let relative = relative_path(path_str, config);
+
+    rules.iter().find_map(|rule| {
+        rule.from.captures(relative).map(|caps| {
+            let mut target = String::new();
+            caps.expand(&rule.to, &mut target);
+
+            match target.strip_prefix('~') {
+                Some(rest) => format!("{target_root}{rest}"),
+                None if Path::new(&target).is_relative() => format!("{target_root}/{target}"),
+                None => target,
+            }
+        })
+    })
+}
+
+/// Extensions of SASS files, which are compiled to CSS unless configured otherwise
+const SASS_EXTENSIONS: [&str; 2] = ["sass", "scss"];
+
+/// SASS compilers, in order of preference, along with the arguments they need besides the
+/// input and output paths
+const SASS_COMPILERS: [(&str, &str); 3] = [
+    ("sass", "--no-source-map"),
+    ("dart-sass", "--no-source-map"),
+    ("sassc", ""),
+];
+
+/// Runs the post-processor configured for the extension of a deployed template (or the
+/// built-in SASS compiler). Unchanged templates are only processed if their output is missing
+/// (e.g. because the post-processor wasn't installed before).
+fn postprocess(path: &Path, written: bool, dotfiles: &Dotfiles, no_sass: bool) {
+    let Some(ext) = path.extension().and_then(OsStr::to_str) else {
+        return;
+    };
+
+    if no_sass && SASS_EXTENSIONS.contains(&ext) {
+        return;
+    }
+
+    let (cmd, output_ext) = match dotfiles.postprocess.get(ext) {
+        Some(p) => (Some(p.cmd.clone()), p.output.as_str()),
+        None if SASS_EXTENSIONS.contains(&ext) => (None, "css"),
+        None => return,
+    };
+    let output = path.with_extension(output_ext);
+
+    if written || !output.exists() {
+        let Some(cmd) = cmd.or_else(sass_command) else {
+            log_warning(&format!(
+                "no SASS compiler found (looked for `sass`, `dart-sass`, `sassc`), not compiling `{}`",
+                path.display()
+            ));
+            return;
+        };
+
+        let cmd = cmd
+            .replace("{input}", &shell_quote(path))
+            .replace("{output}", &shell_quote(&output));
+
+        log_debug(&format!("Running `{cmd}`"));
+
+        if let Err(e) = run_cmd!(sh -c $cmd) {
+            log_error(&e.to_string());
+            return;
+        }
+    }
+
+    // Only the deployed copy is removed, the source in the dotfiles directory is kept
+    if dotfiles.sass.remove_source && SASS_EXTENSIONS.contains(&ext) && output != path {
+        log_debug(&format!("Removing `{}`", path.display()));
+        log_on_err(std::fs::remove_file(path));
+    }
+}
+
+/// Returns the file a template is compiled into, if it's a SASS template that's removed
+/// once it's compiled (see `sass.remove_source`)
+fn compiled_output(target: &Path, dotfiles: &Dotfiles, no_sass: bool) -> Option<PathBuf> {
+    let ext = target.extension().and_then(OsStr::to_str)?;
+
+    if no_sass || !dotfiles.sass.remove_source || !SASS_EXTENSIONS.contains(&ext) {
+        return None;
+    }
+
+    let output_ext = dotfiles
+        .postprocess
+        .get(ext)
+        .map_or("css", |p| p.output.as_str());
+    let output = target.with_extension(output_ext);
+
+    (output != target).then_some(output)
+}
+
+/// Returns the command compiling SASS with the first compiler that's installed
+fn sass_command() -> Option<String> {
+    SASS_COMPILERS
+        .iter()
+        .find(|(c, _)| command_exists(c))
+        .map(|(c, args)| format!("{c} {{input}} {{output}} {args}"))
+}
+
+/// Returns the first SASS compiler that's installed
+pub fn sass_compiler() -> Option<&'static str> {
+    SASS_COMPILERS
+        .iter()
+        .map(|(c, _)| *c)
+        .find(|c| command_exists(c))
+}
+
+/// Quotes a path for use in a shell command
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"))
+}
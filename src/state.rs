@@ -0,0 +1,206 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use dirs::{home_dir, state_dir};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::Config,
+    logging::FileStatus,
+    processing::{Action, Status},
+};
+
+/// File (in the state directory) the last deploys are recorded in
+const STATE_FILE: &str = "deployed.json";
+
+/// Returns the file the deployed files are recorded in, in the configured state directory
+/// (`~/.local/state/dottery` by default)
+pub fn state_path(config: &Config) -> PathBuf {
+    config
+        .paths
+        .state_dir
+        .as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            state_dir()
+                .unwrap_or_else(|| home_dir().unwrap().join(".local/state"))
+                .join("dottery")
+        })
+        .join(STATE_FILE)
+}
+
+/// Hashes the contents of a file
+pub fn hash_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    std::fs::read(path).map(hash_bytes)
+}
+
+/// Hashes some contents, like `hash_file`
+pub fn hash_bytes<B: AsRef<[u8]>>(contents: B) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Files deployed into a directory, by target path relative to it
+pub type Files = BTreeMap<String, FileState>;
+
+/// Record of the files written by deploys, by the directory they were deployed into
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(from = "StoredState")]
+pub struct DeployState {
+    pub roots: BTreeMap<String, Files>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct FileState {
+    /// Source file, relative to the dotfiles directory
+    pub source: String,
+    /// Hash of the deployed contents
+    pub hash: String,
+    /// Hash of the rendered template the file was compiled from, if that isn't kept (see
+    /// `sass.remove_source`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<String>,
+}
+
+/// Formats states are stored in: the current one, and the earlier one recording a single
+/// directory
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredState {
+    Roots { roots: BTreeMap<String, Files> },
+    Single { root: String, files: Files },
+}
+
+impl From<StoredState> for DeployState {
+    fn from(stored: StoredState) -> Self {
+        match stored {
+            StoredState::Roots { roots } => Self { roots },
+            StoredState::Single { root, files } => Self {
+                roots: BTreeMap::from([(root, files)]),
+            },
+        }
+    }
+}
+
+impl DeployState {
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+
+        serde_json::from_str(&contents).map_err(io::Error::from)
+    }
+
+    /// Reads the recorded deploys, starting anew if there are none
+    pub fn read_or_default<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        match Self::read(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            result => result,
+        }
+    }
+
+    /// Returns the targets recorded in a directory that still exist
+    pub fn targets(&self, root: &str) -> Vec<PathBuf> {
+        self.roots
+            .get(root)
+            .into_iter()
+            .flat_map(|files| files.keys())
+            .map(|t| Path::new(root).join(t))
+            .filter(|t| t.symlink_metadata().is_ok())
+            .collect()
+    }
+
+    /// Forgets a file deployed into a directory, and the directory once it has no more
+    pub fn forget(&mut self, root: &str, target: &Path) {
+        let (Some(files), Ok(t)) = (self.roots.get_mut(root), target.strip_prefix(root)) else {
+            return;
+        };
+
+        files.remove(t.to_string_lossy().as_ref());
+
+        if files.is_empty() {
+            self.roots.remove(root);
+        }
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        if let Some(dir) = path.as_ref().parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+
+    /// Records the files that were written (or already up to date) into a directory, along
+    /// with the hashes of their contents
+    pub fn record(&mut self, root: &str, actions: &[Action], dotfiles_path: &str) {
+        let files = self.roots.entry(root.into()).or_default();
+
+        actions
+            .iter()
+            .filter(|a| matches!(a.status, Status::Written | Status::Unchanged))
+            .for_each(|a| {
+                let (Ok(target), Ok(source)) = (
+                    a.target.strip_prefix(root),
+                    a.source.strip_prefix(dotfiles_path),
+                ) else {
+                    return;
+                };
+
+                if let Ok(hash) = hash_file(&a.target) {
+                    files.insert(
+                        target.to_string_lossy().into(),
+                        FileState {
+                            source: source.to_string_lossy().into(),
+                            hash,
+                            input: a.input.clone(),
+                        },
+                    );
+                }
+            });
+    }
+
+    /// Files of all directories, by their path relative to the directory they're in
+    fn all_files(&self) -> BTreeMap<&str, &FileState> {
+        self.roots
+            .values()
+            .flatten()
+            .map(|(path, file)| (path.as_str(), file))
+            .collect()
+    }
+
+    /// Compares the files of two states (e.g. of different machines, by their paths
+    /// relative to the directories they were deployed into). Files only present in `self`
+    /// are reported as [`FileStatus::Missing`], ones only present in `other` as
+    /// [`FileStatus::Extra`].
+    pub fn compare<'a>(&'a self, other: &'a Self) -> Vec<(&'a str, FileStatus)> {
+        let (files, other_files) = (self.all_files(), other.all_files());
+        let mut statuses: Vec<_> = files
+            .iter()
+            .map(|(path, file)| {
+                let status = match other_files.get(path) {
+                    Some(f) if f.hash == file.hash => FileStatus::Identical,
+                    Some(_) => FileStatus::Modified,
+                    None => FileStatus::Missing,
+                };
+
+                (*path, status)
+            })
+            .collect();
+
+        statuses.extend(
+            other_files
+                .keys()
+                .filter(|path| !files.contains_key(*path))
+                .map(|path| (*path, FileStatus::Extra)),
+        );
+
+        statuses.sort_unstable_by_key(|(path, _)| *path);
+
+        statuses
+    }
+}
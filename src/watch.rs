@@ -0,0 +1,76 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    time::Duration,
+};
+
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use crate::logging::{log_error, log_msg};
+
+/// How long to wait for more changes after one, so that e.g. an editor writing a file in
+/// several steps only causes one redeploy
+const DEBOUNCE: Duration = Duration::from_millis(200);
+/// How often to check whether watching was interrupted
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Watches the dotfiles directory (its manifests and everything in `raw/` and `template/`)
+/// and calls `on_change` with the changed files after each burst of changes, until
+/// interrupted with Ctrl-C. A running `on_change` is always finished first.
+pub fn watch(dotfiles_dir: &Path, mut on_change: impl FnMut(Vec<PathBuf>)) -> notify::Result<()> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+
+    if let Err(e) = ctrlc::set_handler(move || flag.store(true, Ordering::Relaxed)) {
+        log_error(&format!("failed to handle Ctrl-C: {e}"));
+    }
+
+    let (sender, events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+
+    watcher.watch(dotfiles_dir, RecursiveMode::NonRecursive)?;
+
+    for dir in ["raw", "template"].map(|d| dotfiles_dir.join(d)) {
+        if dir.exists() {
+            watcher.watch(&dir, RecursiveMode::Recursive)?;
+        }
+    }
+
+    log_msg("Watching for changes (press Ctrl-C to stop)");
+
+    while !interrupted.load(Ordering::Relaxed) {
+        let Ok(event) = events.recv_timeout(POLL_INTERVAL) else {
+            continue;
+        };
+
+        let mut changed = HashSet::new();
+        collect_paths(event, &mut changed);
+
+        while let Ok(event) = events.recv_timeout(DEBOUNCE) {
+            collect_paths(event, &mut changed);
+        }
+
+        if !changed.is_empty() {
+            on_change(changed.into_iter().collect());
+        }
+    }
+
+    log_msg("Stopped watching");
+
+    Ok(())
+}
+
+/// Adds the files created or modified by the event
+fn collect_paths(event: notify::Result<Event>, changed: &mut HashSet<PathBuf>) {
+    match event {
+        Ok(e) if matches!(e.kind, EventKind::Create(_) | EventKind::Modify(_)) => {
+            changed.extend(e.paths.into_iter().filter(|p| p.is_file()))
+        }
+        Ok(_) => (),
+        Err(e) => log_error(&format!("failed to watch files: {e}")),
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use tempfile::TempDir;
+
+/// Dotfiles directory with a config pointing to it, along with a directory to deploy into
+struct Fixture {
+    dir: TempDir,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        let dir = TempDir::new().unwrap();
+        let fixture = Self { dir };
+
+        fixture.write(
+            "config.toml",
+            &format!(
+                "[paths]\ndotfiles_path = {:?}\nstate_dir = {:?}\n",
+                fixture.dotfiles(),
+                fixture.path("state")
+            ),
+        );
+        fixture.write(
+            "dots/..toml",
+            r##"
+            [dottery]
+            packages = []
+
+            [colors]
+            background = "#000000"
+            "##,
+        );
+        fixture.write(
+            "dots/template/.config/kitty/kitty.conf",
+            "background {{ vars.colors.background }}",
+        );
+        fixture.write("dots/raw/.config/gzdoom/gzdoom.ini", "[GlobalSettings]\n");
+
+        fixture
+    }
+
+    fn path(&self, relative: &str) -> PathBuf {
+        self.dir.path().join(relative)
+    }
+
+    fn dotfiles(&self) -> PathBuf {
+        self.path("dots")
+    }
+
+    fn target(&self) -> PathBuf {
+        self.path("home")
+    }
+
+    fn write(&self, relative: &str, contents: &str) {
+        let path = self.path(relative);
+
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    /// Runs `dot deploy` into the target directory with the given extra arguments
+    fn deploy(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_dot"))
+            .arg("--config")
+            .arg(self.path("config.toml"))
+            .arg("deploy")
+            .arg("--target")
+            .arg(self.target())
+            .args(args)
+            .env("HOME", self.dir.path())
+            .output()
+            .unwrap()
+    }
+}
+
+fn read(path: &Path) -> String {
+    fs::read_to_string(path).unwrap()
+}
+
+#[test]
+fn deploys_templates_and_raw_files() {
+    let fixture = Fixture::new();
+
+    let output = fixture.deploy(&[]);
+    assert!(output.status.success(), "{output:?}");
+
+    let target = fixture.target();
+    assert_eq!(
+        read(&target.join(".config/kitty/kitty.conf")),
+        "background #000000"
+    );
+    assert_eq!(
+        fs::read(target.join(".config/gzdoom/gzdoom.ini")).unwrap(),
+        fs::read(fixture.dotfiles().join("raw/.config/gzdoom/gzdoom.ini")).unwrap()
+    );
+}
+
+#[test]
+fn dry_run_writes_nothing() {
+    let fixture = Fixture::new();
+
+    let output = fixture.deploy(&["--dry-run"]);
+    assert!(output.status.success(), "{output:?}");
+
+    assert!(!fixture.target().exists());
+}
+
+#[test]
+fn failed_templates_are_reported() {
+    let fixture = Fixture::new();
+    fixture.write("dots/template/.config/broken", "{{ vars.colors.background ");
+
+    let output = fixture.deploy(&[]);
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+
+    // NOTE: The rest of the dotfiles are still deployed
+    let target = fixture.target();
+    assert!(!target.join(".config/broken").exists());
+    assert!(target.join(".config/kitty/kitty.conf").exists());
+}
+
+#[test]
+fn identical_raw_files_are_left_alone() {
+    let fixture = Fixture::new();
+    assert!(fixture.deploy(&[]).status.success());
+
+    let output = fixture.deploy(&["--format", "json"]);
+    assert!(output.status.success(), "{output:?}");
+
+    let actions: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let raw = actions
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|a| a["kind"] == "raw")
+        .unwrap();
+    assert_eq!(raw["status"], "unchanged");
+}
+
+#[test]
+fn render_errors_point_at_the_template() {
+    let fixture = Fixture::new();
+    fixture.write(
+        "dots/template/.config/broken",
+        "fine\n{{ vars.colors.background ",
+    );
+
+    let output = fixture.deploy(&[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("template/.config/broken:2"), "{stderr}");
+}
+
+#[test]
+fn front_matter_conditions_skip_templates() {
+    let fixture = Fixture::new();
+    fixture.write(
+        "dots/template/.config/skipped",
+        "{# dottery: when = \"vars.colors.background == '#ffffff'\" #}\nlight",
+    );
+    fixture.write(
+        "dots/template/.config/kept",
+        "{# dottery: when = \"vars.colors.background == '#000000'\" #}\ndark",
+    );
+
+    let output = fixture.deploy(&[]);
+    assert!(output.status.success(), "{output:?}");
+
+    let target = fixture.target();
+    assert!(!target.join(".config/skipped").exists());
+    assert_eq!(read(&target.join(".config/kept")), "dark");
+}
+
+#[test]
+fn symlinked_parents_are_followed_with_a_warning() {
+    let fixture = Fixture::new();
+    let elsewhere = fixture.path("elsewhere");
+    fs::create_dir_all(&elsewhere).unwrap();
+    fs::create_dir_all(fixture.target()).unwrap();
+    std::os::unix::fs::symlink(&elsewhere, fixture.target().join(".config")).unwrap();
+
+    let output = fixture.deploy(&["--verbose"]);
+    assert!(output.status.success(), "{output:?}");
+
+    let log = String::from_utf8_lossy(&output.stdout) + String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("resolves to"), "{log}");
+    assert!(
+        log.contains("since one of its parent directories is a symlink"),
+        "{log}"
+    );
+    assert_eq!(
+        read(&elsewhere.join("kitty/kitty.conf")),
+        "background #000000"
+    );
+}
+
+#[test]
+fn templates_are_written_through_symlinked_targets() {
+    let fixture = Fixture::new();
+    let real = fixture.path("real_kitty.conf");
+    let target = fixture.target().join(".config/kitty/kitty.conf");
+    fs::write(&real, "old").unwrap();
+    fs::create_dir_all(target.parent().unwrap()).unwrap();
+    std::os::unix::fs::symlink(&real, &target).unwrap();
+
+    let output = fixture.deploy(&[]);
+    assert!(output.status.success(), "{output:?}");
+
+    assert!(fs::symlink_metadata(&target).unwrap().is_symlink());
+    assert_eq!(read(&real), "background #000000");
+}